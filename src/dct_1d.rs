@@ -21,7 +21,10 @@
  *   Software.
  */
 
-use std;
+// Constants are kept at the precision given in the original source
+#![allow(clippy::excessive_precision, clippy::approx_constant)]
+
+use std::f64::consts::PI;
 
 #[test]
 fn transforms_correctly() {
@@ -142,7 +145,7 @@ pub fn inverse_transform(vector: &mut [f64; 8]) {
 
 /*---- Tables of constants ----*/
 
-const S: [f64; 8] = [
+pub(crate) const S: [f64; 8] = [
     0.353553390593273762200422,
     0.254897789552079584470970,
     0.270598050073098492199862,
@@ -153,11 +156,197 @@ const S: [f64; 8] = [
     1.281457723870753089398043,
 ];
 
-const A: [f64; 6] = [
-    std::f64::NAN,
+pub(crate) const A: [f64; 6] = [
+    f64::NAN,
     0.707106781186547524400844,
     0.541196100146196984399723,
     0.707106781186547524400844,
     1.306562964876376527856643,
     0.382683432365089771728460,
 ];
+
+/*---- Transforms of other lengths ----*/
+
+// Orthonormal DCT type II of any length in place. Uses the 8-point algorithm above for length 8,
+// the fast Lee algorithm for other powers of two and the reference definition otherwise
+pub fn transform_any(vector: &mut [f64]) {
+    let n = vector.len();
+    if let Ok(array) = <&mut [f64; 8]>::try_from(&mut *vector) {
+        transform(array);
+    } else if n.is_power_of_two() {
+        let mut temp = vec![0.; n];
+        lee_transform(vector, &mut temp);
+        for (k, value) in vector.iter_mut().enumerate() {
+            *value *= scale(n, k);
+        }
+    } else {
+        let coefficients = reference_transform(vector);
+        vector.copy_from_slice(&coefficients);
+    }
+}
+
+// Orthonormal DCT type III of any length in place, the inverse of transform_any()
+pub fn inverse_transform_any(vector: &mut [f64]) {
+    let n = vector.len();
+    if let Ok(array) = <&mut [f64; 8]>::try_from(&mut *vector) {
+        inverse_transform(array);
+    } else if n.is_power_of_two() {
+        for (k, value) in vector.iter_mut().enumerate() {
+            *value *= scale(n, k);
+        }
+        let mut temp = vec![0.; n];
+        lee_inverse_transform(vector, &mut temp);
+    } else {
+        let values = reference_inverse_transform(vector);
+        vector.copy_from_slice(&values);
+    }
+}
+
+/*
+ * Unscaled DCT type II of a power-of-two length vector, by the recursive algorithm of
+ * Byeong Gi Lee, 1984, as in Project Nayuki's FastDctLee. The vector and temp have the same length
+ */
+fn lee_transform(vector: &mut [f64], temp: &mut [f64]) {
+    let n = vector.len();
+    if n == 1 {
+        return;
+    }
+    let half = n / 2;
+    for i in 0..half {
+        let x = vector[i];
+        let y = vector[n - 1 - i];
+        temp[i] = x + y;
+        temp[i + half] = (x - y) / (((i as f64 + 0.5) * PI / n as f64).cos() * 2.);
+    }
+    let (temp_low, temp_high) = temp.split_at_mut(half);
+    let (vector_low, vector_high) = vector.split_at_mut(half);
+    lee_transform(temp_low, vector_low);
+    lee_transform(temp_high, vector_high);
+    for i in 0..half - 1 {
+        vector[i * 2] = temp[i];
+        vector[i * 2 + 1] = temp[i + half] + temp[i + half + 1];
+    }
+    vector[n - 2] = temp[half - 1];
+    vector[n - 1] = temp[n - 1];
+}
+
+/*
+ * Unscaled DCT type III of a power-of-two length vector, the transpose of lee_transform(),
+ * computing each output as the sum of every coefficient times its cosine
+ */
+fn lee_inverse_transform(vector: &mut [f64], temp: &mut [f64]) {
+    let n = vector.len();
+    if n == 1 {
+        return;
+    }
+    let half = n / 2;
+    temp[0] = vector[0];
+    temp[half] = vector[1];
+    for i in 1..half {
+        temp[i] = vector[i * 2];
+        temp[i + half] = vector[i * 2 - 1] + vector[i * 2 + 1];
+    }
+    let (temp_low, temp_high) = temp.split_at_mut(half);
+    let (vector_low, vector_high) = vector.split_at_mut(half);
+    lee_inverse_transform(temp_low, vector_low);
+    lee_inverse_transform(temp_high, vector_high);
+    for i in 0..half {
+        let x = temp[i];
+        let y = temp[i + half] / (((i as f64 + 0.5) * PI / n as f64).cos() * 2.);
+        vector[i] = x + y;
+        vector[n - 1 - i] = x - y;
+    }
+}
+
+#[test]
+fn fast_transforms_match_reference() {
+    use crate::generate::Random;
+
+    let mut random = Random::new(1);
+    for n in [1, 2, 4, 8, 16, 32, 64, 12] {
+        for _ in 0..100 {
+            let vector = (0..n)
+                .map(|_| random.below(256) as f64 - 128.)
+                .collect::<Vec<_>>();
+
+            let mut coefficients = vector.clone();
+            transform_any(&mut coefficients);
+            let reference = reference_transform(&vector);
+            for (a, b) in coefficients.iter().zip(&reference) {
+                assert!((a - b).abs() < 1e-9, "length {n}: {a} != {b}");
+            }
+
+            let mut restored = reference.clone();
+            inverse_transform_any(&mut restored);
+            for (a, b) in restored.iter().zip(&vector) {
+                assert!((a - b).abs() < 1e-9, "length {n}: {a} != {b}");
+            }
+        }
+    }
+}
+
+/*---- Reference transforms of any length ----*/
+
+// Orthonormal DCT type II computed directly from its definition in O(n^2) time.
+// Matches transform() at length 8
+pub fn reference_transform(vector: &[f64]) -> Vec<f64> {
+    let n = vector.len();
+    (0..n)
+        .map(|k| {
+            let sum: f64 = vector
+                .iter()
+                .enumerate()
+                .map(|(i, value)| value * basis(n, k, i))
+                .sum();
+            sum * scale(n, k)
+        })
+        .collect()
+}
+
+// Orthonormal DCT type III, the inverse of reference_transform()
+pub fn reference_inverse_transform(vector: &[f64]) -> Vec<f64> {
+    let n = vector.len();
+    (0..n)
+        .map(|i| {
+            vector
+                .iter()
+                .enumerate()
+                .map(|(k, value)| value * scale(n, k) * basis(n, k, i))
+                .sum()
+        })
+        .collect()
+}
+
+fn basis(n: usize, k: usize, i: usize) -> f64 {
+    (PI * ((2 * i + 1) * k) as f64 / (2 * n) as f64).cos()
+}
+
+fn scale(n: usize, k: usize) -> f64 {
+    if k == 0 {
+        (1. / n as f64).sqrt()
+    } else {
+        (2. / n as f64).sqrt()
+    }
+}
+
+#[test]
+fn reference_transform_matches() {
+    let mut vector = [52., 55., 61., 66., 70., 61., 64., 73.];
+    let reference = reference_transform(&vector);
+    transform(&mut vector);
+    for (a, b) in vector.iter().zip(&reference) {
+        assert!((a - b).abs() < 1e-9);
+    }
+
+    for n in [1, 4, 5, 16, 32] {
+        let vector = (0..n).map(|i| ((i * 37) % 23) as f64 - 11.).collect::<Vec<_>>();
+        let coefficients = reference_transform(&vector);
+        // Orthonormal, so energy is preserved
+        let energy = |values: &[f64]| values.iter().map(|v| v * v).sum::<f64>();
+        assert!((energy(&vector) - energy(&coefficients)).abs() < 1e-6);
+        let restored = reference_inverse_transform(&coefficients);
+        for (a, b) in vector.iter().zip(&restored) {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+}
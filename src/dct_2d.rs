@@ -1,53 +1,77 @@
+use std::str::FromStr;
+
 use crate::dct_1d;
+use crate::padding::{self, Padding};
 use crate::yuv4mpeg2::Frame;
 
-type MacroBlock = [[u8; 8]; 8];
+// Pixels of a square block in row-major order
+pub(crate) type MacroBlock = Vec<u8>;
 
-// Quantises in 8x8 blocks
-pub fn quantise_frame(frame: Frame, quantisation_factor: f64) -> Frame {
-    let blocks_y = divide(&frame.data_y, frame.height, frame.width);
-    let coeffs_y = blocks_y.iter().map(|block| transform(*block));
-    let quantised_y = coeffs_y.map(|block| quantise(block, quantisation_factor));
-    let dequantised_y = quantised_y.map(|block| dequantise(block, quantisation_factor));
-    let untransformed = dequantised_y
-        .map(|block| inverse_transform(block))
-        .collect();
-    let data_y = concatenate(untransformed, frame.height, frame.width);
-
-    let new_frame = Frame {
-        width: frame.width,
-        height: frame.height,
-        data_y,
-        data_cb: frame.data_cb,
-        data_cr: frame.data_cr,
-        // data_cb: vec![0; frame.chroma_len()],
-        // data_cr: vec![0; frame.chroma_len()],
-        color_space: frame.color_space,
-    };
+pub const BLOCK_SIZES: [usize; 4] = [4, 8, 16, 32];
+
+// Quantises in square blocks of the given size, with chroma planes quantised at their
+// subsampled size
+pub fn quantise_frame(
+    frame: Frame,
+    quant_matrices: &QuantMatrices,
+    block_size: usize,
+    padding: Padding,
+) -> Frame {
+    let luma_matrix = resample_matrix(&quant_matrices.luma, block_size);
+    let chroma_matrix = resample_matrix(&quant_matrices.chroma, block_size);
+    frame.map_planes(|index, values, width, height| {
+        let quant_matrix = match index {
+            0 => &luma_matrix,
+            _ => &chroma_matrix,
+        };
+        quantise_plane(values, width, height, quant_matrix, block_size, padding)
+    })
+}
 
-    new_frame
+fn quantise_plane(
+    values: &[u8],
+    width: usize,
+    height: usize,
+    quant_matrix: &[f64],
+    block_size: usize,
+    padding: Padding,
+) -> Vec<u8> {
+    let blocks = divide(values, height, width, block_size, padding);
+    let coeffs = blocks.iter().map(|block| transform(block, block_size));
+    let quantised = coeffs.map(|block| quantise(block, quant_matrix));
+    let dequantised = quantised.map(|block| dequantise(block, quant_matrix));
+    let untransformed = dequantised
+        .map(|block| inverse_transform(block, block_size))
+        .collect();
+    concatenate(untransformed, height, width, block_size)
 }
 
 // Joins macroblocks back into a single frame
-// Removes zero-padding to the right and bottom
-fn concatenate(blocks: Vec<MacroBlock>, height: usize, width: usize) -> Vec<u8> {
+// Removes padding to the right and bottom
+pub(crate) fn concatenate(
+    blocks: Vec<MacroBlock>,
+    height: usize,
+    width: usize,
+    block_size: usize,
+) -> Vec<u8> {
     let mut values = vec![0; height * width];
 
-    let block_count_y = (height as f32 / 8.).ceil() as usize;
-    let block_count_x = (width as f32 / 8.).ceil() as usize;
+    let block_count_y = height.div_ceil(block_size);
+    let block_count_x = width.div_ceil(block_size);
 
     for j in 0..block_count_y {
         for i in 0..block_count_x {
-            let block = blocks[j * block_count_x + i];
+            let block = &blocks[j * block_count_x + i];
 
-            let start_x = i * 8;
-            let start_y = j * 8;
-            let end_x = usize::min(start_x + 8, width);
-            let end_y = usize::min(start_y + 8, height);
+            let start_x = i * block_size;
+            let start_y = j * block_size;
+            let end_x = usize::min(start_x + block_size, width);
+            let end_y = usize::min(start_y + block_size, height);
 
             for row in start_y..end_y {
+                let block_row = (row - start_y) * block_size;
                 values[row * width + start_x..row * width + end_x]
-                    .copy_from_slice(&block[row - start_y][0..end_x - start_x]);
+                    .copy_from_slice(&block[block_row..block_row + end_x - start_x]);
             }
         }
     }
@@ -55,7 +79,33 @@ fn concatenate(blocks: Vec<MacroBlock>, height: usize, width: usize) -> Vec<u8>
     values
 }
 
-const QUANT_MATRIX_50: [[f64; 8]; 8] = [
+pub type QuantMatrix = [[f64; 8]; 8];
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unable to parse quantisation matrices: {0}")]
+    ParseQuantMatrices(String),
+}
+
+// Quantisation tables for the luma and chroma planes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantMatrices {
+    pub luma: QuantMatrix,
+    pub chroma: QuantMatrix,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum QuantPreset {
+    /// Luminance and chrominance tables from the JPEG standard (Annex K)
+    AnnexK,
+    /// The same step size of 16 for every coefficient
+    Flat,
+    /// Default MPEG-2 intra matrix, used for both luma and chroma
+    Mpeg2Intra,
+}
+
+// Luminance table from the JPEG standard (Annex K), which corresponds to quality 50
+const QUANT_MATRIX_50: QuantMatrix = [
     [16., 11., 10., 16., 24., 40., 51., 61.],
     [12., 12., 14., 19., 26., 58., 60., 55.],
     [14., 13., 16., 24., 40., 57., 69., 56.],
@@ -65,46 +115,208 @@ const QUANT_MATRIX_50: [[f64; 8]; 8] = [
     [49., 64., 78., 87., 103., 121., 120., 101.],
     [72., 92., 95., 98., 112., 100., 103., 99.],
 ];
-fn quantise(block: [[f64; 8]; 8], quantisation_factor: f64) -> [[f64; 8]; 8] {
-    let mut output_block = block.clone();
-    for i in 0..8 {
-        for j in 0..8 {
-            output_block[j][i] = (output_block[j][i]/(QUANT_MATRIX_50[j][i]*quantisation_factor)).round();
+// Chrominance table from the JPEG standard (Annex K)
+const CHROMA_QUANT_MATRIX_50: QuantMatrix = [
+    [17., 18., 24., 47., 99., 99., 99., 99.],
+    [18., 21., 26., 66., 99., 99., 99., 99.],
+    [24., 26., 56., 99., 99., 99., 99., 99.],
+    [47., 66., 99., 99., 99., 99., 99., 99.],
+    [99., 99., 99., 99., 99., 99., 99., 99.],
+    [99., 99., 99., 99., 99., 99., 99., 99.],
+    [99., 99., 99., 99., 99., 99., 99., 99.],
+    [99., 99., 99., 99., 99., 99., 99., 99.],
+];
+// Default intra matrix from the MPEG-2 standard (ISO/IEC 13818-2)
+const MPEG2_INTRA_QUANT_MATRIX: QuantMatrix = [
+    [8., 16., 19., 22., 26., 27., 29., 34.],
+    [16., 16., 22., 24., 27., 29., 34., 37.],
+    [19., 22., 26., 27., 29., 34., 34., 38.],
+    [22., 22., 26., 27., 29., 34., 37., 40.],
+    [22., 26., 27., 29., 32., 35., 40., 48.],
+    [26., 27., 29., 32., 35., 40., 48., 58.],
+    [26., 27., 29., 34., 38., 46., 56., 69.],
+    [27., 29., 35., 38., 46., 56., 69., 83.],
+];
+
+impl QuantMatrices {
+    pub fn preset(preset: QuantPreset) -> Self {
+        let (luma, chroma) = match preset {
+            QuantPreset::AnnexK => (QUANT_MATRIX_50, CHROMA_QUANT_MATRIX_50),
+            QuantPreset::Flat => ([[16.; 8]; 8], [[16.; 8]; 8]),
+            QuantPreset::Mpeg2Intra => (MPEG2_INTRA_QUANT_MATRIX, MPEG2_INTRA_QUANT_MATRIX),
+        };
+        QuantMatrices { luma, chroma }
+    }
+
+    // The tables multiplied by raw factors (higher results in lower quality)
+    pub fn scaled(&self, luma_factor: f64, chroma_factor: f64) -> Self {
+        QuantMatrices {
+            luma: self.luma.map(|row| row.map(|value| value * luma_factor)),
+            chroma: self
+                .chroma
+                .map(|row| row.map(|value| value * chroma_factor)),
+        }
+    }
+
+    // The tables scaled the same way as libjpeg's quality setting, from 1 to 100,
+    // treating them as the quality 50 tables
+    pub fn with_quality(&self, quality: u8) -> Self {
+        QuantMatrices {
+            luma: scale_for_quality(&self.luma, quality),
+            chroma: scale_for_quality(&self.chroma, quality),
         }
     }
-    output_block
 }
-fn dequantise(block: [[f64; 8]; 8], quantisation_factor: f64) -> [[f64; 8]; 8] {
-    let mut output_block = block.clone();
-    for i in 0..8 {
-        for j in 0..8 {
-            output_block[j][i] = output_block[j][i]*QUANT_MATRIX_50[j][i]*quantisation_factor;
+
+impl Default for QuantMatrices {
+    fn default() -> Self {
+        QuantMatrices::preset(QuantPreset::AnnexK)
+    }
+}
+
+impl FromStr for QuantMatrices {
+    type Err = Error;
+
+    // Parses a [luma] section and an optional [chroma] section, each holding 64 positive values in
+    // row-major order separated by whitespace or commas. Text after a # is a comment. Values
+    // before any section header are taken as the luma matrix, and the chroma matrix defaults to
+    // the luma one:
+    //
+    //   [luma]
+    //   16 11 10 16 24 40 51 61
+    //   ...
+    //   [chroma]
+    //   17 18 24 47 99 99 99 99
+    //   ...
+    fn from_str(matrices_string: &str) -> Result<Self, Error> {
+        let mut luma = vec![];
+        let mut chroma = None;
+        let mut current = &mut luma;
+        for line in matrices_string.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if let Some(section) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                current = match section.trim() {
+                    "luma" => &mut luma,
+                    "chroma" => chroma.insert(vec![]),
+                    other => {
+                        return Err(Error::ParseQuantMatrices(format!(
+                            "unknown section [{other}]"
+                        )))
+                    }
+                };
+                continue;
+            }
+            for value_string in line.split([' ', '\t', ',']).filter(|s| !s.is_empty()) {
+                match value_string.parse::<f64>() {
+                    Ok(value) if value > 0. && value.is_finite() => current.push(value),
+                    _ => {
+                        return Err(Error::ParseQuantMatrices(format!(
+                            "{value_string} isn't a positive number"
+                        )))
+                    }
+                }
+            }
         }
+
+        let luma = to_matrix(&luma, "luma")?;
+        let chroma = match chroma {
+            Some(values) => to_matrix(&values, "chroma")?,
+            None => luma,
+        };
+        Ok(QuantMatrices { luma, chroma })
+    }
+}
+
+fn to_matrix(values: &[f64], name: &str) -> Result<QuantMatrix, Error> {
+    if values.len() != 64 {
+        return Err(Error::ParseQuantMatrices(format!(
+            "{name} matrix has {} values instead of 64",
+            values.len()
+        )));
     }
-    output_block
+    let mut matrix = [[0.; 8]; 8];
+    matrix.as_flattened_mut().copy_from_slice(values);
+    Ok(matrix)
 }
 
-// Splits image data into square macroblocks of size 8x8, adding zero-padding
+// IJG scaling: quality 50 gives the base table, lower qualities scale it up by 50/quality
+// and higher ones scale it down linearly to all ones at 100. Entries are rounded to integers
+// and clamped to 1..255 as for baseline JPEG
+fn scale_for_quality(base: &QuantMatrix, quality: u8) -> QuantMatrix {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+    base.map(|row| row.map(|value| ((value as u32 * scale + 50) / 100).clamp(1, 255) as f64))
+}
+
+// Stretches or shrinks an 8x8 quantisation matrix to the given block size, interpolating
+// linearly so that each coefficient gets the step size of the same spatial frequency in an 8x8
+// block. The transform is orthonormal, so the same step size gives about the same error per
+// pixel at every block size
+pub(crate) fn resample_matrix(matrix: &QuantMatrix, block_size: usize) -> Vec<f64> {
+    let positions = (0..block_size)
+        .map(|k| {
+            let position = k as f64 * 8. / block_size as f64;
+            let lower = (position.floor() as usize).min(7);
+            (lower, (lower + 1).min(7), position - lower as f64)
+        })
+        .collect::<Vec<_>>();
+    let mut resampled = Vec::with_capacity(block_size * block_size);
+    for &(top, bottom, y_fraction) in &positions {
+        for &(left, right, x_fraction) in &positions {
+            let upper = matrix[top][left] + (matrix[top][right] - matrix[top][left]) * x_fraction;
+            let lower =
+                matrix[bottom][left] + (matrix[bottom][right] - matrix[bottom][left]) * x_fraction;
+            resampled.push(upper + (lower - upper) * y_fraction);
+        }
+    }
+    resampled
+}
+
+pub(crate) fn quantise(block: Vec<f64>, quant_matrix: &[f64]) -> Vec<f64> {
+    block
+        .iter()
+        .zip(quant_matrix)
+        .map(|(value, step)| (value / step).round())
+        .collect()
+}
+pub(crate) fn dequantise(block: Vec<f64>, quant_matrix: &[f64]) -> Vec<f64> {
+    block
+        .iter()
+        .zip(quant_matrix)
+        .map(|(value, step)| value * step)
+        .collect()
+}
+
+// Splits image data into square macroblocks, adding padding
 // where the block lies past the edge of the image to the right and/or bottom
-fn divide(values: &[u8], height: usize, width: usize) -> Vec<MacroBlock> {
-    let block_count_y = (height as f32 / 8.).ceil() as usize;
-    let block_count_x = (width as f32 / 8.).ceil() as usize;
+pub(crate) fn divide(
+    values: &[u8],
+    height: usize,
+    width: usize,
+    block_size: usize,
+    padding: Padding,
+) -> Vec<MacroBlock> {
+    let block_count_y = height.div_ceil(block_size);
+    let block_count_x = width.div_ceil(block_size);
 
     let mut blocks = Vec::with_capacity(block_count_y * block_count_x);
 
     for j in 0..block_count_y {
         for i in 0..block_count_x {
-            let mut block = [[0; 8]; 8];
-
-            let start_x = i * 8;
-            let start_y = j * 8;
-            let end_x = usize::min(start_x + 8, width);
-            let end_y = usize::min(start_y + 8, height);
-
-            for row in start_y..end_y {
-                block[row - start_y][0..end_x - start_x]
-                    .copy_from_slice(&values[row * width + start_x..row * width + end_x]);
-            }
+            let mut block = vec![0; block_size * block_size];
+            padding::read_block(
+                values,
+                width,
+                height,
+                (i * block_size, j * block_size),
+                block_size,
+                padding,
+                &mut block,
+            );
             blocks.push(block);
         }
     }
@@ -118,87 +330,80 @@ fn does_divide_into_macroblocks() {
     const WIDTH: usize = 31;
     let data_y = [1; HEIGHT * WIDTH];
 
-    let blocks = divide(&data_y, HEIGHT, WIDTH);
+    let blocks = divide(&data_y, HEIGHT, WIDTH, 8, Padding::Zero);
     assert_eq!(blocks.len(), 12); // check number of blocks
-    assert_eq!(blocks[11][0][0], 1); // check values are copied over
-    assert_eq!(blocks[11][7][7], 0); // check 0 padding
+    assert_eq!(blocks[11][0], 1); // check values are copied over
+    assert_eq!(blocks[11][63], 0); // check 0 padding
+
+    let blocks = divide(&data_y, HEIGHT, WIDTH, 16, Padding::Zero);
+    assert_eq!(blocks.len(), 4);
+    assert_eq!(blocks[3][16 * 6 + 14], 1);
+    assert_eq!(blocks[3][16 * 7 + 15], 0);
 }
 
 // shifts block values from 0,255 to -128,127
-fn shift_and_normalise(block: MacroBlock) -> [[f64; 8]; 8] {
-    let mut new_block = [[0.; 8]; 8];
-
-    for i in 0..8 {
-        for j in 0..8 {
-            new_block[i][j] = (block[i][j] as i16 - 128) as f64;
-        }
-    }
-    new_block
+fn shift_and_normalise(block: &[u8]) -> Vec<f64> {
+    block
+        .iter()
+        .map(|&value| (value as i16 - 128) as f64)
+        .collect()
 }
 
 // maps values from -128,127 to 0,255
-fn unshift_and_denormalise(block: [[f64; 8]; 8]) -> MacroBlock {
-    let mut new_block = [[0; 8]; 8];
-
-    for i in 0..8 {
-        for j in 0..8 {
-            // clamps to valid u8 (between 0 and 255)
-            new_block[i][j] = ((block[i][j] as i16) + 128).max(0).min(255) as u8;
-        }
-    }
-    new_block
+fn unshift_and_denormalise(block: Vec<f64>) -> MacroBlock {
+    block
+        .iter()
+        // clamps to valid u8 (between 0 and 255)
+        .map(|&value| ((value as i16) + 128).clamp(0, 255) as u8)
+        .collect()
 }
 
 // Performs transform as shown at https://en.wikipedia.org/wiki/Discrete_cosine_transform#M-D_DCT-II
-fn transform(block: MacroBlock) -> [[f64; 8]; 8] {
+pub(crate) fn transform(block: &[u8], block_size: usize) -> Vec<f64> {
     // Perform DCT along rows
     let mut shifted_block = shift_and_normalise(block);
-    for k1 in 0..8 {
-        dct_1d::transform(&mut shifted_block[k1]);
+    for row in shifted_block.chunks_exact_mut(block_size) {
+        dct_1d::transform_any(row);
     }
     // Perform DCT along columns
-    for i in 0..8 {
-        let mut column = [0.; 8];
-        for j in 0..8 {
-            column[j] = shifted_block[j][i];
-        }
-
-        dct_1d::transform(&mut column);
-
-        for j in 0..8 {
-            shifted_block[j][i] = column[j];
-        }
-    }
+    transform_columns(&mut shifted_block, block_size, dct_1d::transform_any);
     shifted_block
 }
-fn inverse_transform(coefficients: [[f64; 8]; 8]) -> MacroBlock {
-    let mut intermediate_coeffs = coefficients.clone();
+pub(crate) fn inverse_transform(coefficients: Vec<f64>, block_size: usize) -> MacroBlock {
+    let mut intermediate_coeffs = coefficients;
 
     // Perform DCT along rows
-    for k1 in 0..8 {
-        dct_1d::inverse_transform(&mut intermediate_coeffs[k1]);
+    for row in intermediate_coeffs.chunks_exact_mut(block_size) {
+        dct_1d::inverse_transform_any(row);
     }
     // Perform DCT along columns
-    for i in 0..8 {
-        let mut column = [0.; 8];
-        for j in 0..8 {
-            column[j] = intermediate_coeffs[j][i];
+    transform_columns(
+        &mut intermediate_coeffs,
+        block_size,
+        dct_1d::inverse_transform_any,
+    );
+
+    unshift_and_denormalise(intermediate_coeffs)
+}
+
+fn transform_columns(block: &mut [f64], block_size: usize, transform: fn(&mut [f64])) {
+    let mut column = vec![0.; block_size];
+    for i in 0..block_size {
+        for (value, row) in column.iter_mut().zip(block.chunks_exact(block_size)) {
+            *value = row[i];
         }
 
-        dct_1d::inverse_transform(&mut column);
+        transform(&mut column);
 
-        for j in 0..8 {
-            intermediate_coeffs[j][i] = column[j];
+        for (row, &value) in block.chunks_exact_mut(block_size).zip(&column) {
+            row[i] = value;
         }
     }
-
-    let block = unshift_and_denormalise(intermediate_coeffs);
-    block
 }
 
 #[test]
 fn transforms_correctly() {
-    let test_block: MacroBlock = [
+    let test_block = [
         [52, 55, 61, 66, 70, 61, 64, 73],
         [63, 59, 55, 90, 109, 85, 69, 72],
         [62, 59, 68, 113, 144, 104, 66, 73],
@@ -208,13 +413,317 @@ fn transforms_correctly() {
         [85, 71, 64, 59, 55, 61, 65, 83],
         [87, 79, 69, 68, 65, 76, 78, 94],
     ];
-    let transformed = transform(test_block);
-    dbg!(transformed);
-    let quantised = quantise(transformed, 1.);
-    dbg!(quantised);
-    let dequantised = dequantise(quantised, 1.);
-    dbg!(dequantised);
-    let inv = inverse_transform(dequantised);
-    dbg!(inv);
-    assert_eq!(inv[0][1], test_block[0][0]);
+    let transformed = transform(test_block.as_flattened(), 8);
+    dbg!(&transformed);
+    let quantised = quantise(transformed, QUANT_MATRIX_50.as_flattened());
+    dbg!(&quantised);
+    let dequantised = dequantise(quantised, QUANT_MATRIX_50.as_flattened());
+    dbg!(&dequantised);
+    let inv = inverse_transform(dequantised, 8);
+    dbg!(&inv);
+    // Decompressed block from the worked example at https://en.wikipedia.org/wiki/JPEG#Decoding,
+    // which rounds to nearest where this truncates, so samples can be one level apart
+    let expected_block = [
+        [62, 65, 57, 60, 72, 63, 60, 82],
+        [57, 55, 56, 82, 108, 87, 62, 71],
+        [58, 50, 60, 111, 148, 114, 67, 65],
+        [65, 55, 66, 120, 155, 114, 68, 70],
+        [70, 63, 67, 101, 122, 88, 60, 78],
+        [71, 71, 64, 70, 80, 62, 56, 81],
+        [75, 82, 67, 54, 63, 65, 66, 83],
+        [81, 94, 75, 54, 68, 81, 81, 87],
+    ];
+    for (&value, &expected) in inv.iter().zip(expected_block.as_flattened()) {
+        assert!(value.abs_diff(expected) <= 1, "{value} != {expected}");
+    }
+}
+
+#[test]
+fn edge_padding_improves_psnr() {
+    use crate::{metrics::psnr, yuv4mpeg2::ColorSpace};
+
+    // A bright, smoothly varying image whose size isn't a multiple of 8
+    const WIDTH: usize = 21;
+    const HEIGHT: usize = 19;
+    let frame = Frame {
+        width: WIDTH,
+        height: HEIGHT,
+        color_space: ColorSpace::C444,
+        data_y: (0..WIDTH * HEIGHT)
+            .map(|i| (180 + (i % WIDTH) * 2 + (i / WIDTH)) as u8)
+            .collect(),
+        data_cb: vec![128; WIDTH * HEIGHT],
+        data_cr: vec![128; WIDTH * HEIGHT],
+    };
+
+    // PSNR over the pixels that belong to partial blocks at the right and bottom
+    let edge_psnr = |padding: Padding| {
+        let quantised = quantise_frame(
+            frame.clone(),
+            &QuantMatrices::default().scaled(2., 2.),
+            8,
+            padding,
+        );
+        let (original, reconstructed): (Vec<u8>, Vec<u8>) = (0..WIDTH * HEIGHT)
+            .filter(|i| i % WIDTH >= 16 || i / WIDTH >= 16)
+            .map(|i| (frame.data_y[i], quantised.data_y[i]))
+            .unzip();
+        psnr(&original, &reconstructed)
+    };
+
+    let zero = edge_psnr(Padding::Zero);
+    for padding in [Padding::Replicate, Padding::Mirror, Padding::Mean] {
+        let padded = edge_psnr(padding);
+        dbg!(padding, zero, padded);
+        assert!(padded > zero + 3.);
+    }
+}
+
+#[test]
+fn high_frequencies_are_quantised_hardest() {
+    use crate::{
+        generate::{generate_frame, Pattern},
+        metrics::psnr,
+        yuv4mpeg2::{ColorSpace, Header},
+    };
+
+    let header = Header {
+        width: 64,
+        height: 64,
+        color_space: ColorSpace::C444,
+        ..Header::default()
+    };
+    let quantised_psnr = |pattern: Pattern| {
+        let frame = generate_frame(pattern, &header, 0);
+        let quantised = quantise_frame(
+            frame.clone(),
+            &QuantMatrices::default().scaled(1., 1.),
+            8,
+            Padding::Zero,
+        );
+        psnr(&frame.data_y, &quantised.data_y)
+    };
+
+    // The zone plate's energy is mostly in high frequencies, which have the coarsest quantisers
+    let gradient = quantised_psnr(Pattern::Gradient);
+    let zone_plate = quantised_psnr(Pattern::ZonePlate);
+    dbg!(gradient, zone_plate);
+    assert!(gradient > zone_plate + 10.);
+}
+
+#[test]
+fn quantises_chroma_planes_separately() {
+    use crate::{
+        generate::{generate_frame, Pattern},
+        metrics::psnr,
+        yuv4mpeg2::{ColorSpace, Header},
+    };
+
+    let header = Header {
+        width: 36,
+        height: 20,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let frame = generate_frame(Pattern::Gradient, &header, 0);
+    let chroma_psnr = |chroma_quantisation_factor: f64| {
+        let quantised = quantise_frame(
+            frame.clone(),
+            &QuantMatrices::default().scaled(1., chroma_quantisation_factor),
+            8,
+            Padding::Zero,
+        );
+        assert_eq!(quantised.data_cb.len(), frame.chroma_len());
+        assert_eq!(
+            psnr(&frame.data_y, &quantised.data_y),
+            psnr(
+                &frame.data_y,
+                &quantise_frame(
+                    frame.clone(),
+                    &QuantMatrices::default().scaled(1., 1.),
+                    8,
+                    Padding::Zero
+                )
+                .data_y
+            )
+        );
+        psnr(&frame.data_cb, &quantised.data_cb)
+    };
+    // The chroma factor only affects the chroma planes
+    let fine = chroma_psnr(0.5);
+    let coarse = chroma_psnr(8.);
+    dbg!(fine, coarse);
+    assert!(fine > coarse);
+
+    // Monochrome frames have empty chroma planes
+    let mono = generate_frame(
+        Pattern::Gradient,
+        &Header {
+            color_space: ColorSpace::Cmono,
+            ..header
+        },
+        0,
+    );
+    let quantised = quantise_frame(
+        mono,
+        &QuantMatrices::default().scaled(1., 1.),
+        8,
+        Padding::Zero,
+    );
+    assert!(quantised.data_cb.is_empty() && quantised.data_cr.is_empty());
+}
+
+#[test]
+fn scales_tables_like_libjpeg() {
+    // Quality 50 is the standard table, 100 is all ones and 1 saturates at 255
+    assert_eq!(
+        QuantMatrices::default().with_quality(50),
+        QuantMatrices::default().scaled(1., 1.)
+    );
+    let best = QuantMatrices::default().with_quality(100);
+    assert!(best
+        .luma
+        .iter()
+        .chain(&best.chroma)
+        .flatten()
+        .all(|&value| value == 1.));
+    let worst = QuantMatrices::default().with_quality(1);
+    assert_eq!(worst.luma[0][0], 255.);
+    assert_eq!(worst.chroma[7][7], 255.);
+
+    // Values from libjpeg's jpeg_set_quality, including its integer rounding
+    let q75 = QuantMatrices::default().with_quality(75);
+    assert_eq!(q75.luma[0], [8., 6., 5., 8., 12., 20., 26., 31.]);
+    assert_eq!(q75.chroma[0][0], 9.);
+    let q10 = QuantMatrices::default().with_quality(10);
+    assert_eq!(q10.luma[0][..3], [80., 55., 50.]);
+    assert_eq!(q10.chroma[0][0], 85.);
+}
+
+#[test]
+fn parses_quantisation_matrices() {
+    let annex_k = QuantMatrices::preset(QuantPreset::AnnexK);
+    let rows = |matrix: &QuantMatrix| {
+        matrix
+            .iter()
+            .map(|row| row.map(|value| value.to_string()).join(" "))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    let both = format!(
+        "# Annex K\n[luma]\n{}\n\n[chroma] # chrominance\n{}\n",
+        rows(&annex_k.luma),
+        rows(&annex_k.chroma).replace(' ', ", ")
+    );
+    assert_eq!(QuantMatrices::from_str(&both).unwrap(), annex_k);
+
+    // Without a chroma section the luma matrix is used for both
+    let flat = QuantMatrices::from_str(&"16 ".repeat(64)).unwrap();
+    assert_eq!(flat, QuantMatrices::preset(QuantPreset::Flat));
+
+    for bad in [
+        "16 ".repeat(63),
+        "16 ".repeat(63) + "0",
+        "16 ".repeat(63) + "x",
+        format!("[luma]\n{}[alpha]\n", "16 ".repeat(64)),
+        format!("[luma]\n{}[chroma]\n{}", "16 ".repeat(64), "16 ".repeat(65)),
+    ] {
+        assert!(QuantMatrices::from_str(&bad).is_err());
+    }
+}
+
+#[test]
+fn quantises_with_any_block_size() {
+    use crate::{
+        generate::{generate_frame, Pattern},
+        metrics::psnr,
+        yuv4mpeg2::{ColorSpace, Header},
+    };
+
+    // The 8x8 matrices are used unchanged at size 8, and sampled at the same spatial frequencies
+    // at other sizes: every other step at 4, and interpolated between steps at 16
+    let annex_k = QuantMatrices::default();
+    assert_eq!(resample_matrix(&annex_k.luma, 8), annex_k.luma.as_flattened());
+    let small = resample_matrix(&annex_k.luma, 4);
+    assert_eq!(small[..4], [16., 10., 24., 51.]);
+    let large = resample_matrix(&annex_k.luma, 16);
+    assert_eq!(large[..3], [16., 13.5, 11.]);
+    assert_eq!(resample_matrix(&annex_k.luma, 32).len(), 32 * 32);
+
+    // Every size round trips almost exactly with fine steps, including partial edge blocks
+    let header = Header {
+        width: 44,
+        height: 20,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let frame = generate_frame(Pattern::ZonePlate, &header, 0);
+    for block_size in BLOCK_SIZES {
+        let quantised = quantise_frame(
+            frame.clone(),
+            &QuantMatrices::preset(QuantPreset::Flat).scaled(1. / 16., 1. / 16.),
+            block_size,
+            Padding::Replicate,
+        );
+        assert_eq!(quantised.chroma_len(), frame.chroma_len());
+        let luma = psnr(&frame.data_y, &quantised.data_y);
+        dbg!(block_size, luma);
+        assert!(luma > 45.);
+    }
+}
+
+#[test]
+fn quantises_interlaced_frames_end_to_end() {
+    use crate::{
+        generate::{generate_frame, Pattern},
+        interlace,
+        metrics::psnr,
+        yuv4mpeg2::{ColorSpace, Decoder, Encoder, Header, InterlaceMode},
+    };
+
+    // Each field of a 4:2:0 frame 10 lines high has an odd number of chroma lines
+    let header = Header {
+        width: 16,
+        height: 10,
+        interlace_mode: InterlaceMode::It,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let write = |frames: &[Frame]| {
+        let mut bytes = vec![];
+        let mut writer = Encoder::new(&mut bytes).write_header(&header).unwrap();
+        for frame in frames {
+            writer.write_frame(frame.clone()).unwrap();
+        }
+        drop(writer);
+        bytes
+    };
+    let frames = (0..2)
+        .map(|index| generate_frame(Pattern::ZonePlate, &header, index))
+        .collect::<Vec<_>>();
+    let input = write(&frames);
+
+    let reader = Decoder::new(input.as_slice()).read_header().unwrap();
+    let quantised = reader
+        .into_iter()
+        .map(|frame| {
+            interlace::map_fields(&frame.unwrap(), |field| {
+                quantise_frame(field, &QuantMatrices::default(), 8, Padding::Replicate)
+            })
+        })
+        .collect::<Vec<_>>();
+    let output = write(&quantised);
+    assert_eq!(output.len(), input.len());
+
+    let decoded = Decoder::new(output.as_slice())
+        .read_header()
+        .unwrap()
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(decoded.len(), frames.len());
+    for (frame, decoded) in frames.iter().zip(&decoded) {
+        assert!(psnr(&frame.data_cb, &decoded.data_cb) > 20.);
+    }
 }
@@ -1,80 +1,412 @@
-use crate::{yuv4mpeg2::Frame, dct_1d};
+use std::{collections::VecDeque, f64::consts::PI, str::FromStr};
 
-// Accepts length eight vector of frames
+use crate::{
+    dct_1d,
+    dct_2d::{self, QuantMatrices},
+    padding::{self, Padding},
+    yuv4mpeg2::Frame,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TemporalMode {
+    /// 1D DCT along time at each pixel
+    Time,
+    /// Separable 3D DCT over each spatial block across the frames of a chunk
+    Cube,
+}
+
+// Step sizes indexed by temporal frequency, then spatial frequency in row-major order
+pub type CubeQuantMatrix = Vec<Vec<f64>>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unable to parse temporal weights, expected 8 comma-separated positive numbers")]
+    ParseWeights,
+}
+
+// Multipliers of the quantisation factor for each temporal frequency of an 8 frame chunk,
+// from DC (static) up to the fastest flicker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Weights(pub [f64; 8]);
+
+// Separate weights for the luma and chroma planes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TemporalWeights {
+    pub luma: Weights,
+    pub chroma: Weights,
+}
+
+// Static detail is kept most accurately, with steps growing linearly with frequency since
+// fast changes are harder to see. Chroma is less visible still, so gets twice the step size
+pub const DEFAULT_LUMA_WEIGHTS: Weights = Weights([1., 2., 3., 4., 5., 6., 7., 8.]);
+pub const DEFAULT_CHROMA_WEIGHTS: Weights = Weights([2., 4., 6., 8., 10., 12., 14., 16.]);
+
+impl Default for TemporalWeights {
+    fn default() -> Self {
+        TemporalWeights {
+            luma: DEFAULT_LUMA_WEIGHTS,
+            chroma: DEFAULT_CHROMA_WEIGHTS,
+        }
+    }
+}
+
+impl FromStr for Weights {
+    type Err = Error;
+
+    // Parses weights of the form 1,2,3,4,5,6,7,8
+    fn from_str(weights_string: &str) -> Result<Self, Error> {
+        let values = weights_string
+            .split(',')
+            .map(|value| match value.trim().parse::<f64>() {
+                Ok(value) if value > 0. && value.is_finite() => Ok(value),
+                _ => Err(Error::ParseWeights),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Weights(values.try_into().map_err(|_| Error::ParseWeights)?))
+    }
+}
+
+impl Weights {
+    // Weights for a chunk of the given length, interpolated linearly so that each
+    // temporal frequency gets the weight of the same frequency in an 8 frame chunk
+    pub fn resample(&self, length: usize) -> Vec<f64> {
+        (0..length)
+            .map(|k| {
+                let position = k as f64 * 8. / length as f64;
+                let lower = (position.floor() as usize).min(7);
+                let upper = (lower + 1).min(7);
+                let fraction = position - lower as f64;
+                self.0[lower] + (self.0[upper] - self.0[lower]) * fraction
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Weights {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let values = self.0.map(|value| value.to_string());
+        write!(f, "{}", values.join(","))
+    }
+}
+
+// Accepts a chunk of frames
 // For each of the Y, Cb, and Cr components,
 // and for each 2D pixel position in the image,
 // it performs a 1D DCT along the frames i.e. in the time dimension
-// Quantises and dequantises the resulting coefficients
+// Quantises and dequantises the resulting coefficients, with a step size for each
+// temporal frequency of the quantisation factor times its weight
 // Performs the inverse transform
 // Returns vector containing the same frames post-quantisation
-pub fn quantise_chunk(chunk: Vec<Frame>, quantisation_factor: f64) -> Vec<Frame> {
-    let mut quantised_chunk = chunk.clone();
+pub fn quantise_chunk(
+    chunk: Vec<Frame>,
+    quantisation_factor: f64,
+    weights: &TemporalWeights,
+) -> Vec<Frame> {
+    let length = chunk.len();
+    let steps = |weights: &Weights| {
+        weights
+            .resample(length)
+            .iter()
+            .map(|weight| quantisation_factor * weight)
+            .collect::<Vec<_>>()
+    };
+    let (luma_steps, chroma_steps) = (steps(&weights.luma), steps(&weights.chroma));
+
+    // Quantised planes indexed by plane and then frame
+    let mut planes = (0..3)
+        .map(|index| {
+            let steps = match index {
+                0 => &luma_steps,
+                _ => &chroma_steps,
+            };
+            let sources = chunk
+                .iter()
+                .map(|frame| frame.planes()[index].0)
+                .collect::<Vec<_>>();
+            let mut quantised = vec![vec![0; sources[0].len()]; length];
 
-    // Loop through pixel coordinates, perform 1D dct along frames at each coordinate
+            // Loop through pixel coordinates, perform 1D dct along frames at each coordinate
+            for pixel_index in 0..sources[0].len() {
+                let mut temporal_vector = sources
+                    .iter()
+                    .map(|values| shift(values[pixel_index]))
+                    .collect::<Vec<_>>();
+                dct_1d::transform_any(&mut temporal_vector);
+                quantise(&mut temporal_vector, steps);
+                dequantise(&mut temporal_vector, steps);
+                dct_1d::inverse_transform_any(&mut temporal_vector);
+                for (frame, value) in quantised.iter_mut().zip(temporal_vector) {
+                    frame[pixel_index] = unshift(value);
+                }
+            }
+            quantised
+        })
+        .collect::<Vec<_>>();
 
-    // Y component
-    for pixel_index in 0..chunk[0].data_y.len() {
-        let mut temporal_vector = [0.; 8];
-        for i in 0..8 {
-            temporal_vector[i] = shift(chunk[i].data_y[pixel_index]);
+    chunk
+        .iter()
+        .enumerate()
+        .map(|(t, frame)| frame.map_planes(|index, _, _, _| std::mem::take(&mut planes[index][t])))
+        .collect()
+}
+
+// Accepts a chunk of frames
+// Splits each of the Y, Cb and Cr planes into 8x8 blocks as in the 2D mode, and
+// transforms each block stacked across the frames as a cube: a 2D DCT of every
+// frame's block followed by a 1D DCT along time at every spatial frequency
+// Quantises and dequantises the coefficients with the cube matrix built from the spatial
+// matrices and temporal weights, then performs the inverse transform
+pub fn quantise_cubes(
+    chunk: Vec<Frame>,
+    quant_matrices: &QuantMatrices,
+    weights: &TemporalWeights,
+    block_size: usize,
+    padding: Padding,
+) -> Vec<Frame> {
+    let length = chunk.len();
+    let cube_matrix = |spatial, weights: &Weights| {
+        cube_quant_matrix(
+            &dct_2d::resample_matrix(spatial, block_size),
+            &weights.resample(length),
+        )
+    };
+    let luma_matrix = cube_matrix(&quant_matrices.luma, &weights.luma);
+    let chroma_matrix = cube_matrix(&quant_matrices.chroma, &weights.chroma);
+
+    // Quantised planes indexed by plane and then frame
+    let mut planes = (0..3)
+        .map(|index| {
+            let (_, width, height) = chunk[0].planes()[index];
+            let blocks = chunk
+                .iter()
+                .map(|frame| {
+                    dct_2d::divide(frame.planes()[index].0, height, width, block_size, padding)
+                })
+                .collect::<Vec<_>>();
+            let quant_matrix = match index {
+                0 => &luma_matrix,
+                _ => &chroma_matrix,
+            };
+            quantise_cube_blocks(blocks, quant_matrix, block_size)
+                .into_iter()
+                .map(|frame_blocks| dct_2d::concatenate(frame_blocks, height, width, block_size))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    chunk
+        .iter()
+        .enumerate()
+        .map(|(t, frame)| frame.map_planes(|index, _, _, _| std::mem::take(&mut planes[index][t])))
+        .collect()
+}
+
+// Splits a sequence of frames into chunks, quantises each chunk and joins them back together.
+// With overlap, chunks start every half chunk, and each output frame is the average of the two
+// chunks covering it weighted by a sine-squared window, which fades each chunk in and out so
+// there is no jump in quality at chunk boundaries. Any short chunk at the end is padded
+pub struct TemporalQuantiser<I, F> {
+    source: I,
+    quantise: F,
+    chunk_length: usize,
+    hop: usize,
+    padding: Padding,
+    // Source frames from the start of the next chunk
+    window: VecDeque<Frame>,
+    // Weighted sums of the quantised frames from the start of the next chunk, and their weights
+    sums: VecDeque<(Frame, [Vec<f64>; 3], f64)>,
+    output: VecDeque<Frame>,
+    ended: bool,
+}
+
+impl<I, F> TemporalQuantiser<I, F>
+where
+    I: Iterator<Item = Frame>,
+    F: FnMut(Vec<Frame>) -> Vec<Frame>,
+{
+    pub fn new(
+        source: I,
+        chunk_length: usize,
+        overlap: bool,
+        padding: Padding,
+        quantise: F,
+    ) -> Self {
+        TemporalQuantiser {
+            source,
+            quantise,
+            chunk_length,
+            hop: if overlap {
+                chunk_length / 2
+            } else {
+                chunk_length
+            }
+            .max(1),
+            padding,
+            window: VecDeque::with_capacity(chunk_length),
+            sums: VecDeque::with_capacity(chunk_length),
+            output: VecDeque::with_capacity(chunk_length),
+            ended: false,
         }
-        dct_1d::transform(&mut temporal_vector);
-        quantise(&mut temporal_vector, quantisation_factor);
-        dequantise(&mut temporal_vector, quantisation_factor);
-        dct_1d::inverse_transform(&mut temporal_vector);
-        for i in 0..8 {
-            quantised_chunk[i].data_y[pixel_index] = unshift(temporal_vector[i]);
+    }
+
+    // Weight of the frame at a position within a chunk. The squared sines of two chunks
+    // half a chunk apart sum to one
+    fn weight(&self, position: usize) -> f64 {
+        if self.hop == self.chunk_length {
+            1.
+        } else {
+            (PI * (position as f64 + 0.5) / self.chunk_length as f64)
+                .sin()
+                .powi(2)
         }
     }
 
-    // Cb component
-    for pixel_index in 0..chunk[0].data_cb.len() {
-        let mut temporal_vector = [0.; 8];
-        for i in 0..8 {
-            temporal_vector[i] = shift(chunk[i].data_cb[pixel_index]);
+    fn quantise_next_chunk(&mut self) {
+        while !self.ended && self.window.len() < self.chunk_length {
+            match self.source.next() {
+                Some(frame) => self.window.push_back(frame),
+                None => self.ended = true,
+            }
         }
-        dct_1d::transform(&mut temporal_vector);
-        quantise(&mut temporal_vector, quantisation_factor);
-        dequantise(&mut temporal_vector, quantisation_factor);
-        dct_1d::inverse_transform(&mut temporal_vector);
-        for i in 0..8 {
-            quantised_chunk[i].data_cb[pixel_index] = unshift(temporal_vector[i]);
+        let length = self.window.len();
+        if length == 0 {
+            return;
         }
-    }
+        let chunk = pad_chunk(
+            self.window.iter().cloned().collect(),
+            self.chunk_length,
+            self.padding,
+        );
+        let quantised = (self.quantise)(chunk);
 
-    // Cr component
-    for pixel_index in 0..chunk[0].data_cr.len() {
-        let mut temporal_vector = [0.; 8];
-        for i in 0..8 {
-            temporal_vector[i] = shift(chunk[i].data_cr[pixel_index]);
+        for (position, frame) in quantised.into_iter().take(length).enumerate() {
+            let weight = self.weight(position);
+            if position == self.sums.len() {
+                let zeros = frame.planes().map(|(values, _, _)| vec![0.; values.len()]);
+                self.sums.push_back((frame.clone(), zeros, 0.));
+            }
+            let (_, sums, total_weight) = &mut self.sums[position];
+            for (sum, (values, _, _)) in sums.iter_mut().zip(frame.planes()) {
+                for (sum, &value) in sum.iter_mut().zip(values) {
+                    *sum += value as f64 * weight;
+                }
+            }
+            *total_weight += weight;
         }
-        dct_1d::transform(&mut temporal_vector);
-        quantise(&mut temporal_vector, quantisation_factor);
-        dequantise(&mut temporal_vector, quantisation_factor);
-        dct_1d::inverse_transform(&mut temporal_vector);
-        for i in 0..8 {
-            quantised_chunk[i].data_cr[pixel_index] = unshift(temporal_vector[i]);
+
+        // Frames before the next chunk's start won't receive any more contributions
+        let finished = if self.ended { length } else { self.hop };
+        for _ in 0..finished {
+            self.window.pop_front();
+            let Some((frame, sums, total_weight)) = self.sums.pop_front() else {
+                break;
+            };
+            self.output.push_back(frame.map_planes(|index, _, _, _| {
+                sums[index]
+                    .iter()
+                    .map(|sum| (sum / total_weight).round().clamp(0., 255.) as u8)
+                    .collect()
+            }));
         }
     }
-
-    quantised_chunk
 }
 
+impl<I, F> Iterator for TemporalQuantiser<I, F>
+where
+    I: Iterator<Item = Frame>,
+    F: FnMut(Vec<Frame>) -> Vec<Frame>,
+{
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.output.is_empty() && !(self.ended && self.window.is_empty()) {
+            self.quantise_next_chunk();
+        }
+        self.output.pop_front()
+    }
+}
 
-// Divides each element by the quantisation factor and rounds the result to the
-// nearest integer
-fn quantise(vector: &mut [f64; 8], quantisation_factor: f64) {
-    for elem in vector.iter_mut() {
-        *elem = (*elem / quantisation_factor).round();
+// Extends a short chunk, such as the last one in a clip, to the full chunk length by filling
+// in the frames after its end. The extra frames should be discarded after quantisation
+pub fn pad_chunk(mut chunk: Vec<Frame>, chunk_length: usize, padding: Padding) -> Vec<Frame> {
+    let length = chunk.len();
+    for position in length..chunk_length {
+        let frame = match padding {
+            Padding::Zero => chunk[0].map_planes(|_, values, _, _| vec![0; values.len()]),
+            Padding::Replicate => chunk[length - 1].clone(),
+            Padding::Mirror => chunk[padding::mirror(position, length)].clone(),
+            Padding::Mean => {
+                let planes = chunk[..length]
+                    .iter()
+                    .map(|frame| frame.planes())
+                    .collect::<Vec<_>>();
+                chunk[0].map_planes(|index, values, _, _| {
+                    (0..values.len())
+                        .map(|i| {
+                            let sum: usize = planes.iter().map(|p| p[index].0[i] as usize).sum();
+                            (sum / length) as u8
+                        })
+                        .collect()
+                })
+            }
+        };
+        chunk.push(frame);
     }
+    chunk
+}
+
+// Outer product of a spatial quantisation matrix and the weights of each temporal frequency
+pub fn cube_quant_matrix(spatial: &[f64], weights: &[f64]) -> CubeQuantMatrix {
+    weights
+        .iter()
+        .map(|weight| spatial.iter().map(|value| value * weight).collect())
+        .collect()
 }
-// Multplies by the quantisation factor
-fn dequantise(vector: &mut [f64; 8], quantisation_factor: f64) {
-    for elem in vector.iter_mut() {
-        *elem = *elem * quantisation_factor;
+
+// Takes the blocks of one plane in each frame of a chunk, and returns them quantised
+fn quantise_cube_blocks(
+    mut blocks: Vec<Vec<dct_2d::MacroBlock>>,
+    quant_matrix: &CubeQuantMatrix,
+    block_size: usize,
+) -> Vec<Vec<dct_2d::MacroBlock>> {
+    for block_index in 0..blocks[0].len() {
+        let mut cube = blocks
+            .iter()
+            .map(|frame_blocks| dct_2d::transform(&frame_blocks[block_index], block_size))
+            .collect::<Vec<_>>();
+        for frequency in 0..block_size * block_size {
+            let mut temporal_vector = cube
+                .iter()
+                .map(|slice| slice[frequency])
+                .collect::<Vec<_>>();
+            dct_1d::transform_any(&mut temporal_vector);
+            for (coefficient, steps) in temporal_vector.iter_mut().zip(quant_matrix) {
+                *coefficient = (*coefficient / steps[frequency]).round() * steps[frequency];
+            }
+            dct_1d::inverse_transform_any(&mut temporal_vector);
+            for (slice, value) in cube.iter_mut().zip(temporal_vector) {
+                slice[frequency] = value;
+            }
+        }
+        for (slice, frame_blocks) in cube.into_iter().zip(blocks.iter_mut()) {
+            frame_blocks[block_index] = dct_2d::inverse_transform(slice, block_size);
+        }
     }
+    blocks
 }
 
+// Divides each element by its step size and rounds the result to the nearest integer
+fn quantise(vector: &mut [f64], steps: &[f64]) {
+    for (elem, step) in vector.iter_mut().zip(steps) {
+        *elem = (*elem / step).round();
+    }
+}
+// Multplies by the step size
+fn dequantise(vector: &mut [f64], steps: &[f64]) {
+    for (elem, step) in vector.iter_mut().zip(steps) {
+        *elem *= step;
+    }
+}
 
 // Shifts values from the range [0,255] to [-128.0,127.0]
 fn shift(value: u8) -> f64 {
@@ -82,5 +414,271 @@ fn shift(value: u8) -> f64 {
 }
 // Shifts values from the range [-128.0,127.0] to [0,255]
 fn unshift(value: f64) -> u8 {
-    ((value as i16) + 128).max(0).min(255) as u8
+    ((value as i16) + 128).clamp(0, 255) as u8
+}
+
+#[test]
+fn weights_low_temporal_frequencies_most() {
+    use crate::{metrics::psnr, yuv4mpeg2::ColorSpace};
+
+    assert_eq!(
+        Weights::from_str("1, 2,3,4,5,6,7,8").unwrap(),
+        DEFAULT_LUMA_WEIGHTS
+    );
+    assert_eq!(
+        Weights::from_str(&DEFAULT_CHROMA_WEIGHTS.to_string()).unwrap(),
+        DEFAULT_CHROMA_WEIGHTS
+    );
+    for bad in [
+        "1,2,3",
+        "1,2,3,4,5,6,7,8,9",
+        "1,2,3,4,5,6,7,0",
+        "1,2,3,4,5,6,7,x",
+    ] {
+        assert!(Weights::from_str(bad).is_err());
+    }
+
+    // A static picture plus a flicker at the highest temporal frequency
+    let chunk = (0..8)
+        .map(|t| Frame {
+            width: 4,
+            height: 4,
+            color_space: ColorSpace::C444,
+            data_y: (0..16).map(|i| 100 + i * 4 + (t % 2) as u8 * 6).collect(),
+            data_cb: vec![128; 16],
+            data_cr: vec![128; 16],
+        })
+        .collect::<Vec<_>>();
+    let quantise = |weights: Weights| {
+        quantise_chunk(
+            chunk.clone(),
+            4.,
+            &TemporalWeights {
+                luma: weights,
+                chroma: weights,
+            },
+        )
+    };
+    // Average of each pixel over the chunk, which is all that the DC coefficient carries
+    let static_picture = |frames: &[Frame]| {
+        (0..16)
+            .map(|i| {
+                (frames
+                    .iter()
+                    .map(|frame| frame.data_y[i] as f64)
+                    .sum::<f64>()
+                    / 8.) as u8
+            })
+            .collect::<Vec<_>>()
+    };
+    let static_psnr =
+        |weights: Weights| psnr(&static_picture(&chunk), &static_picture(&quantise(weights)));
+
+    // The default weights keep the static picture more accurately than weights favouring
+    // high frequencies, and remove the flicker entirely
+    let default = static_psnr(DEFAULT_LUMA_WEIGHTS);
+    let inverted = static_psnr(Weights([8., 7., 6., 5., 4., 3., 2., 1.]));
+    dbg!(default, inverted);
+    assert!(default > inverted);
+    let quantised = quantise(DEFAULT_LUMA_WEIGHTS);
+    assert_eq!(quantised[0].data_y, quantised[1].data_y);
+}
+
+#[test]
+fn quantises_cubes() {
+    use crate::{
+        dct_2d::QuantPreset,
+        generate::{generate_frame, Pattern},
+        metrics::psnr,
+        yuv4mpeg2::{ColorSpace, Header},
+    };
+
+    // A size that isn't a multiple of 8 in either plane
+    let header = Header {
+        width: 20,
+        height: 12,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let moving = (0..8)
+        .map(|t| generate_frame(Pattern::Gradient, &header, t))
+        .collect::<Vec<_>>();
+    let flat = TemporalWeights {
+        luma: Weights([1.; 8]),
+        chroma: Weights([1.; 8]),
+    };
+
+    // Unit step sizes leave only rounding error
+    let fine = QuantMatrices::preset(QuantPreset::Flat).scaled(1. / 16., 1. / 16.);
+    let quantised = quantise_cubes(moving.clone(), &fine, &flat, 8, Padding::Replicate);
+    for (original, quantised) in moving.iter().zip(&quantised) {
+        assert_eq!(quantised.data_cb.len(), original.chroma_len());
+        assert!(original
+            .to_vec()
+            .iter()
+            .zip(quantised.to_vec())
+            .all(|(&a, b)| a.abs_diff(b) <= 1));
+    }
+
+    // A static chunk stays static, and coarser temporal weights cost more on moving content
+    let still = vec![moving[0].clone(); 8];
+    let quantised = quantise_cubes(
+        still,
+        &QuantMatrices::default(),
+        &flat,
+        8,
+        Padding::Replicate,
+    );
+    assert!(quantised
+        .iter()
+        .all(|frame| frame.data_y == quantised[0].data_y));
+    let moving_psnr = |weights: &TemporalWeights| {
+        let quantised = quantise_cubes(
+            moving.clone(),
+            &QuantMatrices::default(),
+            weights,
+            8,
+            Padding::Replicate,
+        );
+        let original = moving.iter().flat_map(|frame| frame.data_y.clone());
+        let reconstructed = quantised.iter().flat_map(|frame| frame.data_y.clone());
+        psnr(
+            &original.collect::<Vec<_>>(),
+            &reconstructed.collect::<Vec<_>>(),
+        )
+    };
+    let flat_psnr = moving_psnr(&flat);
+    let weighted_psnr = moving_psnr(&TemporalWeights::default());
+    dbg!(flat_psnr, weighted_psnr);
+    assert!(flat_psnr > weighted_psnr);
+}
+
+#[test]
+fn pads_tail_chunks() {
+    use crate::yuv4mpeg2::ColorSpace;
+
+    let chunk = (0..3)
+        .map(|t| Frame {
+            width: 2,
+            height: 2,
+            color_space: ColorSpace::C444,
+            data_y: vec![t * 10 + 10; 4],
+            data_cb: vec![128; 4],
+            data_cr: vec![128; 4],
+        })
+        .collect::<Vec<_>>();
+    let first_values = |padding: Padding| {
+        pad_chunk(chunk.clone(), 8, padding)
+            .iter()
+            .map(|frame| frame.data_y[0])
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(first_values(Padding::Zero), [10, 20, 30, 0, 0, 0, 0, 0]);
+    assert_eq!(
+        first_values(Padding::Replicate),
+        [10, 20, 30, 30, 30, 30, 30, 30]
+    );
+    assert_eq!(
+        first_values(Padding::Mirror),
+        [10, 20, 30, 30, 20, 10, 10, 10]
+    );
+    assert_eq!(
+        first_values(Padding::Mean),
+        [10, 20, 30, 20, 20, 20, 20, 20]
+    );
+
+    // A full chunk is left alone
+    let full = pad_chunk(vec![chunk[0].clone(); 8], 8, Padding::Replicate);
+    assert_eq!(full.len(), 8);
+}
+
+#[test]
+fn quantises_chunks_of_any_length() {
+    use crate::{
+        generate::{generate_frame, Pattern},
+        metrics::psnr,
+        yuv4mpeg2::{ColorSpace, Header},
+    };
+
+    assert_eq!(DEFAULT_LUMA_WEIGHTS.resample(8), DEFAULT_LUMA_WEIGHTS.0);
+    assert_eq!(DEFAULT_LUMA_WEIGHTS.resample(4), [1., 3., 5., 7.]);
+    assert_eq!(DEFAULT_LUMA_WEIGHTS.resample(16)[..4], [1., 1.5, 2., 2.5]);
+
+    let header = Header {
+        width: 16,
+        height: 8,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let frames = (0..37)
+        .map(|t| generate_frame(Pattern::Gradient, &header, t))
+        .collect::<Vec<_>>();
+    let weights = TemporalWeights::default();
+
+    // Per-frame PSNR of the luma plane after quantising in chunks
+    let quantised_psnrs = |chunk_length: usize, overlap: bool| {
+        let quantiser = TemporalQuantiser::new(
+            frames.clone().into_iter(),
+            chunk_length,
+            overlap,
+            Padding::Replicate,
+            |chunk| quantise_chunk(chunk, 4., &weights),
+        );
+        let quantised = quantiser.collect::<Vec<_>>();
+        assert_eq!(quantised.len(), frames.len());
+        frames
+            .iter()
+            .zip(&quantised)
+            .map(|(original, quantised)| psnr(&original.data_y, &quantised.data_y))
+            .collect::<Vec<_>>()
+    };
+
+    for chunk_length in [4, 8, 16, 32] {
+        for overlap in [false, true] {
+            let psnrs = quantised_psnrs(chunk_length, overlap);
+            assert!(psnrs.iter().all(|&psnr| psnr > 25.));
+        }
+    }
+
+    // A slow fade, which each chunk approximates with a few temporal frequencies. Without
+    // overlap the error jumps between the last frame of one chunk and the first of the next,
+    // whereas overlapping chunks fade between their approximations
+    let fade = (0..40)
+        .map(|t| Frame {
+            width: 4,
+            height: 4,
+            color_space: ColorSpace::C444,
+            data_y: vec![40 + t * 4; 16],
+            data_cb: vec![128; 16],
+            data_cr: vec![128; 16],
+        })
+        .collect::<Vec<_>>();
+    let largest_jump = |chunk_length: usize, overlap: bool| {
+        let quantised = TemporalQuantiser::new(
+            fade.clone().into_iter(),
+            chunk_length,
+            overlap,
+            Padding::Replicate,
+            |chunk| quantise_chunk(chunk, 4., &weights),
+        )
+        .collect::<Vec<_>>();
+        let errors = fade
+            .iter()
+            .zip(&quantised)
+            .map(|(original, quantised)| quantised.data_y[0] as i16 - original.data_y[0] as i16)
+            .collect::<Vec<_>>();
+        errors
+            .windows(2)
+            .map(|pair| pair[1].abs_diff(pair[0]))
+            .max()
+            .unwrap_or_default()
+    };
+    for chunk_length in [4, 8, 16, 32] {
+        let separate = largest_jump(chunk_length, false);
+        let overlapped = largest_jump(chunk_length, true);
+        dbg!(chunk_length, separate, overlapped);
+        assert!(overlapped <= separate);
+    }
+    assert!(largest_jump(8, true) < largest_jump(8, false));
 }
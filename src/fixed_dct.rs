@@ -0,0 +1,308 @@
+use crate::dct_1d::{A, S};
+
+// Fixed-point version of the 8-point AAN transforms in dct_1d, using only integer arithmetic so
+// the results don't depend on the platform's floating point. Values are i32 with a configurable
+// number of fractional bits; products are formed in i64 and rounded back
+
+pub const MAX_FRACTION_BITS: u32 = 16;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Fixed-point values must have from 1 to {MAX_FRACTION_BITS} fractional bits, not {0}")]
+    FractionBits(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedDct {
+    fraction_bits: u32,
+    a: [i32; 6],
+    s: [i32; 8],
+    // Constants of the inverse transform, which divides by the forward constants
+    inverse_s: [i32; 8],
+    inverse_a1: i32,
+    inverse_a3: i32,
+    inverse_determinant: i32,
+}
+
+impl FixedDct {
+    // More fractional bits give more accurate results but less headroom: pixel values need 12
+    // integer bits in the intermediate sums, so at most MAX_FRACTION_BITS are allowed
+    pub fn new(fraction_bits: u32) -> Result<Self, Error> {
+        if !(1..=MAX_FRACTION_BITS).contains(&fraction_bits) {
+            return Err(Error::FractionBits(fraction_bits));
+        }
+        let fixed = |value: f64| (value * (1 << fraction_bits) as f64).round() as i32;
+        let determinant = A[2] * A[5] - A[2] * A[4] - A[4] * A[5];
+        Ok(FixedDct {
+            fraction_bits,
+            a: [
+                0,
+                fixed(A[1]),
+                fixed(A[2]),
+                fixed(A[3]),
+                fixed(A[4]),
+                fixed(A[5]),
+            ],
+            s: S.map(fixed),
+            inverse_s: S.map(|value| fixed(1. / value)),
+            inverse_a1: fixed(1. / A[1]),
+            inverse_a3: fixed(1. / A[3]),
+            inverse_determinant: fixed(1. / determinant),
+        })
+    }
+
+    pub fn fraction_bits(&self) -> u32 {
+        self.fraction_bits
+    }
+
+    pub fn to_fixed(&self, value: f64) -> i32 {
+        (value * (1 << self.fraction_bits) as f64).round() as i32
+    }
+
+    pub fn from_integer(&self, value: i32) -> i32 {
+        value << self.fraction_bits
+    }
+
+    pub fn to_float(&self, value: i32) -> f64 {
+        value as f64 / (1 << self.fraction_bits) as f64
+    }
+
+    // Nearest integer, with halves rounded up
+    pub fn to_integer(&self, value: i32) -> i32 {
+        (value + (1 << (self.fraction_bits - 1))) >> self.fraction_bits
+    }
+
+    fn multiply(&self, value: i32, constant: i32) -> i32 {
+        let product = value as i64 * constant as i64;
+        ((product + (1 << (self.fraction_bits - 1))) >> self.fraction_bits) as i32
+    }
+
+    // Scaled DCT type II in place, as dct_1d::transform()
+    pub fn transform(&self, vector: &mut [i32; 8]) {
+        let (a, s) = (&self.a, &self.s);
+        let v0 = vector[0] + vector[7];
+        let v1 = vector[1] + vector[6];
+        let v2 = vector[2] + vector[5];
+        let v3 = vector[3] + vector[4];
+        let v4 = vector[3] - vector[4];
+        let v5 = vector[2] - vector[5];
+        let v6 = vector[1] - vector[6];
+        let v7 = vector[0] - vector[7];
+
+        let v8 = v0 + v3;
+        let v9 = v1 + v2;
+        let v10 = v1 - v2;
+        let v11 = v0 - v3;
+        let v12 = -v4 - v5;
+        let v13 = self.multiply(v5 + v6, a[3]);
+        let v14 = v6 + v7;
+
+        let v15 = v8 + v9;
+        let v16 = v8 - v9;
+        let v17 = self.multiply(v10 + v11, a[1]);
+        let v18 = self.multiply(v12 + v14, a[5]);
+
+        let v19 = -self.multiply(v12, a[2]) - v18;
+        let v20 = self.multiply(v14, a[4]) - v18;
+
+        let v21 = v17 + v11;
+        let v22 = v11 - v17;
+        let v23 = v13 + v7;
+        let v24 = v7 - v13;
+
+        let v25 = v19 + v24;
+        let v26 = v23 + v20;
+        let v27 = v23 - v20;
+        let v28 = v24 - v19;
+
+        vector[0] = self.multiply(v15, s[0]);
+        vector[1] = self.multiply(v26, s[1]);
+        vector[2] = self.multiply(v21, s[2]);
+        vector[3] = self.multiply(v28, s[3]);
+        vector[4] = self.multiply(v16, s[4]);
+        vector[5] = self.multiply(v25, s[5]);
+        vector[6] = self.multiply(v22, s[6]);
+        vector[7] = self.multiply(v27, s[7]);
+    }
+
+    // Scaled DCT type III in place, as dct_1d::inverse_transform()
+    pub fn inverse_transform(&self, vector: &mut [i32; 8]) {
+        let (a, inverse_s) = (&self.a, &self.inverse_s);
+        let half = |value: i32| (value + 1) >> 1;
+
+        let v15 = self.multiply(vector[0], inverse_s[0]);
+        let v26 = self.multiply(vector[1], inverse_s[1]);
+        let v21 = self.multiply(vector[2], inverse_s[2]);
+        let v28 = self.multiply(vector[3], inverse_s[3]);
+        let v16 = self.multiply(vector[4], inverse_s[4]);
+        let v25 = self.multiply(vector[5], inverse_s[5]);
+        let v22 = self.multiply(vector[6], inverse_s[6]);
+        let v27 = self.multiply(vector[7], inverse_s[7]);
+
+        let v19 = half(v25 - v28);
+        let v20 = half(v26 - v27);
+        let v23 = half(v26 + v27);
+        let v24 = half(v25 + v28);
+
+        let v7 = half(v23 + v24);
+        let v11 = half(v21 + v22);
+        let v13 = half(v23 - v24);
+        let v17 = half(v21 - v22);
+
+        let v8 = half(v15 + v16);
+        let v9 = half(v15 - v16);
+
+        let v18 = self.multiply(v19 - v20, a[5]);
+        let v12 = self.multiply(self.multiply(v19, a[4]) - v18, self.inverse_determinant);
+        let v14 = self.multiply(v18 - self.multiply(v20, a[2]), self.inverse_determinant);
+
+        let v6 = v14 - v7;
+        let v5 = self.multiply(v13, self.inverse_a3) - v6;
+        let v4 = -v5 - v12;
+        let v10 = self.multiply(v17, self.inverse_a1) - v11;
+
+        let v0 = half(v8 + v11);
+        let v1 = half(v9 + v10);
+        let v2 = half(v9 - v10);
+        let v3 = half(v8 - v11);
+
+        vector[0] = half(v0 + v7);
+        vector[1] = half(v1 + v6);
+        vector[2] = half(v2 + v5);
+        vector[3] = half(v3 + v4);
+        vector[4] = half(v3 - v4);
+        vector[5] = half(v2 - v5);
+        vector[6] = half(v1 - v6);
+        vector[7] = half(v0 - v7);
+    }
+}
+
+#[test]
+fn matches_floating_point_transform() {
+    use crate::{dct_1d, generate::Random};
+
+    // The largest error shrinks as fractional bits are added
+    let mut random = Random::new(3);
+    let mut previous_error = f64::INFINITY;
+    for fraction_bits in [4, 8, 12, 16] {
+        let dct = FixedDct::new(fraction_bits).unwrap();
+        let mut max_error: f64 = 0.;
+        for _ in 0..1000 {
+            let vector: [f64; 8] = std::array::from_fn(|_| random.below(256) as f64 - 128.);
+            let mut expected = vector;
+            dct_1d::transform(&mut expected);
+            let mut fixed = vector.map(|value| dct.to_fixed(value));
+            dct.transform(&mut fixed);
+            for (&a, b) in fixed.iter().zip(expected) {
+                max_error = max_error.max((dct.to_float(a) - b).abs());
+            }
+
+            let mut restored = fixed;
+            dct.inverse_transform(&mut restored);
+            for (&a, b) in restored.iter().zip(vector) {
+                max_error = max_error.max((dct.to_float(a) - b).abs());
+            }
+        }
+        dbg!(fraction_bits, max_error);
+        assert!(max_error < previous_error);
+        previous_error = max_error;
+    }
+    assert!(previous_error < 0.01);
+
+    // Without fractional bits nothing rounds, and with too many the intermediate sums overflow
+    for fraction_bits in [0, MAX_FRACTION_BITS + 1] {
+        assert!(matches!(
+            FixedDct::new(fraction_bits),
+            Err(Error::FractionBits(bits)) if bits == fraction_bits
+        ));
+    }
+}
+
+// Accuracy test for 8x8 inverse DCTs in the style of IEEE 1180-1990: random blocks are
+// transformed in floating point, rounded and clipped to integer coefficients, then inverse
+// transformed both with the fixed-point transform and in floating point. The rounded outputs are
+// compared against the standard's limits
+#[test]
+fn meets_ieee_1180_accuracy() {
+    use crate::{dct_1d, generate::Random};
+
+    // Separable 2D transform of an 8x8 block with a 1D transform applied in place
+    fn transform_2d<T: Copy>(block: &mut [T; 64], transform: impl Fn(&mut [T; 8])) {
+        for row in block.chunks_exact_mut(8) {
+            transform(row.try_into().unwrap());
+        }
+        for u in 0..8 {
+            let mut column: [T; 8] = std::array::from_fn(|v| block[v * 8 + u]);
+            transform(&mut column);
+            for (v, value) in column.into_iter().enumerate() {
+                block[v * 8 + u] = value;
+            }
+        }
+    }
+
+    // Coefficients and the floating-point inverse of each block, for each of the standard's
+    // ranges of input, positive and negated
+    let blocks = 10000;
+    let cases = [
+        (256, 1.),
+        (5, 1.),
+        (300, 1.),
+        (256, -1.),
+        (5, -1.),
+        (300, -1.),
+    ]
+    .map(|(range, sign)| {
+        let mut random = Random::new(range as u64);
+        (0..blocks)
+            .map(|_| {
+                let mut block: [f64; 64] = std::array::from_fn(|_| {
+                    sign * (random.below(2 * range + 1) as f64 - range as f64)
+                });
+                transform_2d(&mut block, dct_1d::transform);
+                let coefficients = block.map(|value| (value.round() as i32).clamp(-2048, 2047));
+                let mut reference = coefficients.map(|value| value as f64);
+                transform_2d(&mut reference, dct_1d::inverse_transform);
+                (
+                    coefficients,
+                    reference.map(|value| (value.round() as i32).clamp(-256, 255)),
+                )
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let passes = |fraction_bits: u32| {
+        let dct = FixedDct::new(fraction_bits).unwrap();
+        let inverse = |coefficients: &[i32; 64]| {
+            let mut values = coefficients.map(|value| dct.from_integer(value));
+            transform_2d(&mut values, |vector| dct.inverse_transform(vector));
+            values.map(|value| dct.to_integer(value).clamp(-256, 255))
+        };
+
+        let meets_limits = |case: &Vec<([i32; 64], [i32; 64])>| {
+            let (mut peak, mut errors, mut squared_errors) = (0, [0i64; 64], [0i64; 64]);
+            for (coefficients, reference) in case {
+                for (index, (a, b)) in inverse(coefficients).into_iter().zip(reference).enumerate()
+                {
+                    let error = (a - b) as i64;
+                    peak = peak.max(error.abs());
+                    errors[index] += error;
+                    squared_errors[index] += error * error;
+                }
+            }
+            let per_pixel = |sums: [i64; 64]| sums.map(|sum| sum as f64 / blocks as f64);
+            let (mean_errors, mean_squared_errors) = (per_pixel(errors), per_pixel(squared_errors));
+            let overall = |values: [f64; 64]| values.iter().sum::<f64>() / 64.;
+            peak <= 1
+                && mean_squared_errors.iter().all(|&value| value <= 0.06)
+                && overall(mean_squared_errors) <= 0.02
+                && mean_errors.iter().all(|value| value.abs() <= 0.015)
+                && overall(mean_errors).abs() <= 0.0015
+        };
+
+        // All-zero input must also give all-zero output
+        cases.iter().all(meets_limits) && inverse(&[0; 64]) == [0; 64]
+    };
+    assert!(passes(13));
+    assert!(passes(MAX_FRACTION_BITS));
+    assert!(!passes(4));
+}
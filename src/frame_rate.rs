@@ -0,0 +1,171 @@
+use std::{collections::VecDeque, str::FromStr};
+
+use crate::yuv4mpeg2::{Frame, Header};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unable to parse frame rate, expected NUMERATOR:DENOMINATOR or a whole number")]
+    ParseFrameRate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRate {
+    pub numerator: usize,
+    pub denominator: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RateConversion {
+    /// Drop or duplicate frames, taking the most recent source frame at each output time
+    Nearest,
+    /// Linearly blend the two source frames either side of each output time
+    Blend,
+}
+
+impl FromStr for FrameRate {
+    type Err = Error;
+
+    // Parses a frame rate given either as a ratio (30000:1001) or a whole number of frames per second
+    fn from_str(rate_string: &str) -> Result<Self, Error> {
+        let (numerator, denominator) = rate_string.split_once(':').unwrap_or((rate_string, "1"));
+        let rate = FrameRate {
+            numerator: numerator.parse().map_err(|_| Error::ParseFrameRate)?,
+            denominator: denominator.parse().map_err(|_| Error::ParseFrameRate)?,
+        };
+        if rate.numerator == 0 || rate.denominator == 0 {
+            return Err(Error::ParseFrameRate);
+        }
+        Ok(rate)
+    }
+}
+
+// Resamples a sequence of frames in time from the header's frame rate to a new one
+pub struct FrameRateConverter<I: Iterator<Item = Frame>> {
+    source: I,
+    method: RateConversion,
+    // Ratio of source frames to output frames, as (input rate x output period)
+    step_numerator: usize,
+    step_denominator: usize,
+    output_index: usize,
+    // Source frames around the current output time, starting at source index window_start
+    window: VecDeque<Frame>,
+    window_start: usize,
+}
+
+impl<I: Iterator<Item = Frame>> FrameRateConverter<I> {
+    pub fn new(source: I, header: &Header, output_rate: FrameRate, method: RateConversion) -> Self {
+        FrameRateConverter {
+            source,
+            method,
+            step_numerator: header.frame_rate_numerator * output_rate.denominator,
+            step_denominator: (header.frame_rate_denominator * output_rate.numerator).max(1),
+            output_index: 0,
+            window: VecDeque::with_capacity(2),
+            window_start: 0,
+        }
+    }
+}
+
+// Header of the resampled output
+pub fn output_header(header: &Header, output_rate: FrameRate) -> Header {
+    let mut output_header = *header;
+    output_header.frame_rate_numerator = output_rate.numerator;
+    output_header.frame_rate_denominator = output_rate.denominator;
+    output_header
+}
+
+impl<I: Iterator<Item = Frame>> Iterator for FrameRateConverter<I> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Position of this output frame on the source timeline, in source frames
+        let position = self.output_index * self.step_numerator;
+        let index = position / self.step_denominator;
+        let fraction = (position % self.step_denominator) as f64 / self.step_denominator as f64;
+
+        while self.window_start < index && !self.window.is_empty() {
+            self.window.pop_front();
+            self.window_start += 1;
+        }
+        while self.window.len() < 2 {
+            match self.source.next() {
+                Some(_) if self.window_start + self.window.len() < index => self.window_start += 1,
+                Some(frame) => self.window.push_back(frame),
+                None => break,
+            }
+        }
+        if self.window_start != index || self.window.is_empty() {
+            return None; // the source has ended
+        }
+
+        self.output_index += 1;
+        match (self.method, self.window.get(1)) {
+            (RateConversion::Blend, Some(next)) if fraction > 0. => {
+                Some(blend(&self.window[0], next, fraction))
+            }
+            _ => Some(self.window[0].clone()),
+        }
+    }
+}
+
+// Weighted average of two frames, with `fraction` being the weight of the second
+fn blend(first: &Frame, second: &Frame, fraction: f64) -> Frame {
+    let second_planes = second.planes();
+    first.map_planes(|index, values, _, _| {
+        values
+            .iter()
+            .zip(second_planes[index].0)
+            .map(|(&a, &b)| (a as f64 * (1. - fraction) + b as f64 * fraction).round() as u8)
+            .collect()
+    })
+}
+
+#[test]
+fn converts_frame_rates() {
+    use crate::yuv4mpeg2::ColorSpace;
+
+    let frames = |count: usize| {
+        (0..count).map(|k| Frame {
+            width: 2,
+            height: 2,
+            color_space: ColorSpace::C444,
+            data_y: vec![k as u8 * 10; 4],
+            data_cb: vec![128; 4],
+            data_cr: vec![128; 4],
+        })
+    };
+    let header = |rate: usize| Header {
+        frame_rate_numerator: rate,
+        frame_rate_denominator: 1,
+        ..Header::default()
+    };
+    let rate = |rate_string: &str| FrameRate::from_str(rate_string).unwrap();
+    let first_values = |frames: Vec<Frame>| {
+        frames
+            .iter()
+            .map(|frame| frame.data_y[0])
+            .collect::<Vec<_>>()
+    };
+
+    // 60 to 30 drops every other frame
+    let halved =
+        FrameRateConverter::new(frames(6), &header(60), rate("30"), RateConversion::Nearest);
+    assert_eq!(first_values(halved.collect()), vec![0, 20, 40]);
+
+    // 24 to 25 duplicates one frame in every 24
+    let sped_up = FrameRateConverter::new(
+        frames(24),
+        &header(24),
+        rate("25:1"),
+        RateConversion::Nearest,
+    );
+    let mut values = first_values(sped_up.collect());
+    assert_eq!(values.len(), 25);
+    values.dedup();
+    assert_eq!(values.len(), 24);
+
+    // 30 to 60 by blending inserts the average of neighbouring frames, holding the last frame
+    let doubled =
+        FrameRateConverter::new(frames(3), &header(30), rate("60"), RateConversion::Blend);
+    assert_eq!(first_values(doubled.collect()), vec![0, 5, 10, 15, 20, 20]);
+}
@@ -0,0 +1,195 @@
+use std::f64::consts::PI;
+
+use crate::yuv4mpeg2::{Frame, Header};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Pattern {
+    /// Static 75% colour bars
+    Bars,
+    /// Circular zone plate sweeping up to the Nyquist frequency, with its phase changing over time
+    ZonePlate,
+    /// Horizontal luma ramp and vertical chroma ramp that scroll across the frame
+    Gradient,
+    /// Rows of random glyph-like shapes scrolling to the left
+    Text,
+    /// Uniform random noise in every plane, different in every frame
+    Noise,
+    /// Checkerboard of 16 pixel squares panning diagonally
+    Checkerboard,
+}
+
+// Deterministic pseudo-random number generator (SplitMix64), for noise that must be
+// repeatable from run to run, such as in randomised tests
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        hash(self.0)
+    }
+
+    // Uniformly distributed in [0, bound)
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound.max(1) as u64) as usize
+    }
+}
+
+// SplitMix64 output function, which mixes all bits of the input into the output
+fn hash(value: u64) -> u64 {
+    let mut z = value;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Generates frame number `index` of a test pattern, with the size and color space given in the header
+pub fn generate_frame(pattern: Pattern, header: &Header, index: usize) -> Frame {
+    let (width, height) = (header.width, header.height);
+    let samples = (0..width * height)
+        .map(|i| sample(pattern, i % width, i / width, index, width, height))
+        .collect::<Vec<_>>();
+
+    let mut frame = Frame {
+        width,
+        height,
+        color_space: header.color_space,
+        data_y: samples.iter().map(|sample| sample[0]).collect(),
+        data_cb: vec![],
+        data_cr: vec![],
+    };
+    frame.data_cb = subsample(&samples, &frame, 1);
+    frame.data_cr = subsample(&samples, &frame, 2);
+    frame
+}
+
+// Averages one component of the full resolution samples down to the frame's chroma plane size.
+// The last chroma column or row of an odd sized frame repeats the frame's last column or row
+fn subsample(samples: &[[u8; 3]], frame: &Frame, component: usize) -> Vec<u8> {
+    let (horizontal, vertical) = frame.color_space.subsampling();
+    let mut plane = Vec::with_capacity(frame.chroma_len());
+    for y in 0..frame.chroma_height() {
+        for x in 0..frame.chroma_width() {
+            let sum: usize = (0..vertical)
+                .flat_map(|j| (0..horizontal).map(move |i| (x * horizontal + i, y * vertical + j)))
+                .map(|(px, py)| {
+                    let (px, py) = (px.min(frame.width - 1), py.min(frame.height - 1));
+                    samples[py * frame.width + px][component] as usize
+                })
+                .sum();
+            plane.push((sum / (horizontal * vertical)) as u8);
+        }
+    }
+    plane
+}
+
+// Y, Cb and Cr values of the pattern at a pixel in frame t
+fn sample(pattern: Pattern, x: usize, y: usize, t: usize, width: usize, height: usize) -> [u8; 3] {
+    match pattern {
+        Pattern::Bars => {
+            // White, yellow, cyan, green, magenta, red, blue
+            const BARS: [[f64; 3]; 7] = [
+                [0.75, 0.75, 0.75],
+                [0.75, 0.75, 0.],
+                [0., 0.75, 0.75],
+                [0., 0.75, 0.],
+                [0.75, 0., 0.75],
+                [0.75, 0., 0.],
+                [0., 0., 0.75],
+            ];
+            rgb_to_ycbcr(BARS[x * BARS.len() / width])
+        }
+        Pattern::ZonePlate => {
+            // The local frequency grows linearly with distance from the centre,
+            // reaching half a cycle per pixel at the nearest edge
+            let dx = x as f64 - width as f64 / 2.;
+            let dy = y as f64 - height as f64 / 2.;
+            let radius = (width.min(height) as f64 / 2.).max(1.);
+            let phase = PI * (dx * dx + dy * dy) / (2. * radius) + t as f64 * PI / 8.;
+            [clamp(128. + 127. * phase.cos()), 128, 128]
+        }
+        Pattern::Gradient => {
+            let luma = (x + 2 * t) % width * 256 / width;
+            let chroma = (y + t) % height * 256 / height;
+            [luma as u8, chroma as u8, (255 - chroma) as u8]
+        }
+        Pattern::Text => {
+            // 8x12 character cells, each holding a random 5x7 glyph with a one pixel border
+            let scrolled_x = x + 2 * t;
+            let (column, row) = (scrolled_x / 8, y / 12);
+            let (glyph_x, glyph_y) = (scrolled_x % 8, y % 12);
+            let lit = (1..6).contains(&glyph_x) && (2..9).contains(&glyph_y) && {
+                let bits = hash(((row as u64) << 32) ^ column as u64);
+                bits >> ((glyph_y - 2) * 5 + glyph_x - 1) & 1 == 1
+            };
+            [if lit { 235 } else { 16 }, 128, 128]
+        }
+        Pattern::Noise => {
+            let bits = hash(((t as u64) << 40) ^ ((y as u64) << 20) ^ x as u64);
+            [bits as u8, (bits >> 8) as u8, (bits >> 16) as u8]
+        }
+        Pattern::Checkerboard => {
+            let square = ((x + 3 * t) / 16 + (y + 2 * t) / 16) % 2;
+            [if square == 0 { 16 } else { 235 }, 128, 128]
+        }
+    }
+}
+
+// Converts RGB in [0, 1] to 8-bit BT.601 studio range YCbCr
+fn rgb_to_ycbcr([r, g, b]: [f64; 3]) -> [u8; 3] {
+    [
+        clamp(16. + 65.481 * r + 128.553 * g + 24.966 * b),
+        clamp(128. - 37.797 * r - 74.203 * g + 112. * b),
+        clamp(128. + 112. * r - 93.786 * g - 18.214 * b),
+    ]
+}
+
+fn clamp(value: f64) -> u8 {
+    value.round().clamp(0., 255.) as u8
+}
+
+#[test]
+fn generates_patterns_deterministically() {
+    use crate::yuv4mpeg2::ColorSpace;
+
+    for color_space in [
+        ColorSpace::C420jpeg,
+        ColorSpace::C422,
+        ColorSpace::C444,
+        ColorSpace::Cmono,
+    ] {
+        let header = Header {
+            width: 38,
+            height: 22,
+            color_space,
+            ..Header::default()
+        };
+        for pattern in [
+            Pattern::Bars,
+            Pattern::ZonePlate,
+            Pattern::Gradient,
+            Pattern::Text,
+            Pattern::Noise,
+            Pattern::Checkerboard,
+        ] {
+            let frame = generate_frame(pattern, &header, 3);
+            assert_eq!(frame.to_vec().len(), header.frame_bytes_length());
+            assert_eq!(frame.to_vec(), generate_frame(pattern, &header, 3).to_vec());
+        }
+    }
+
+    // Top-left bar is 75% white
+    let header = Header {
+        width: 16,
+        height: 16,
+        ..Header::default()
+    };
+    let bars = generate_frame(Pattern::Bars, &header, 0);
+    assert_eq!(
+        (bars.data_y[0], bars.data_cb[0], bars.data_cr[0]),
+        (180, 128, 128)
+    );
+}
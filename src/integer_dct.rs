@@ -0,0 +1,244 @@
+use crate::dct_2d::{self, MacroBlock};
+use crate::padding::Padding;
+use crate::yuv4mpeg2::Frame;
+
+// Integer transform and quantisation in the style of HEVC (ITU-T H.265). Only integer arithmetic
+// with fixed shifts is used, so the output is the same on every platform
+
+pub const MAX_QP: u8 = 51;
+
+// Magnitude of 64·√2·cos(mπ/64) for m = 0..=32 as in the HEVC core transform matrix, whose
+// entries were rounded by hand to keep the rows close to orthogonal. m = 0 is only used by the
+// first row, which is 64 rather than 90 so that it has the same norm as the others
+const COSINES: [i32; 33] = [
+    64, 90, 90, 90, 89, 88, 87, 85, 83, 82, 80, 78, 75, 73, 70, 67, 64, 61, 57, 54, 50, 46, 43, 38,
+    36, 31, 25, 22, 18, 13, 9, 4, 0,
+];
+
+// Forward quantiser multipliers and inverse scales for QP % 6. Each step of 6 in QP doubles the
+// step size, which is 1 at QP 4
+const QUANT_SCALES: [i32; 6] = [26214, 23302, 20560, 18396, 16384, 14564];
+const LEVEL_SCALES: [i32; 6] = [40, 45, 51, 57, 64, 72];
+
+const BIT_DEPTH: u32 = 8;
+const MAX_TRANSFORM_RANGE: u32 = 15;
+
+// Quantises in square blocks of the given size (4, 8, 16 or 32) with a flat quantiser whose
+// step size is 2^((qp - 4) / 6)
+pub fn quantise_frame(frame: Frame, qp: u8, block_size: usize, padding: Padding) -> Frame {
+    let matrix = transform_matrix(block_size);
+    frame.map_planes(|_, values, width, height| {
+        let blocks = dct_2d::divide(values, height, width, block_size, padding)
+            .iter()
+            .map(|block| {
+                let coefficients = transform(block, &matrix, block_size);
+                let levels = quantise(&coefficients, qp, block_size);
+                inverse_transform(&dequantise(&levels, qp, block_size), &matrix, block_size)
+            })
+            .collect();
+        dct_2d::concatenate(blocks, height, width, block_size)
+    })
+}
+
+// Rows of the size-point transform are basis functions, scaled by 64·√size
+pub fn transform_matrix(size: usize) -> Vec<i32> {
+    let period = 4 * 32;
+    let mut matrix = Vec::with_capacity(size * size);
+    for k in 0..size {
+        for n in 0..size {
+            // Angle in units of π/64, folded into the first half turn
+            let mut m = (2 * n + 1) * k * (32 / size) % period;
+            if m > period / 2 {
+                m = period - m;
+            }
+            matrix.push(match m {
+                0..=32 => COSINES[m],
+                _ => -COSINES[64 - m],
+            });
+        }
+    }
+    matrix
+}
+
+// Separable forward transform of a block of pixels. The shifts keep every intermediate value
+// within 16 bits
+pub fn transform(block: &[u8], matrix: &[i32], size: usize) -> Vec<i32> {
+    let log2_size = size.ilog2();
+    let residual = block
+        .iter()
+        .map(|&value| value as i32 - 128)
+        .collect::<Vec<_>>();
+    let columns = multiply(matrix, &residual, size, false, log2_size + BIT_DEPTH - 9);
+    multiply(&columns, matrix, size, true, log2_size + 6)
+}
+
+pub fn inverse_transform(coefficients: &[i32], matrix: &[i32], size: usize) -> MacroBlock {
+    let columns = multiply_transposed(matrix, coefficients, size, 7)
+        .into_iter()
+        .map(clip_16)
+        .collect::<Vec<_>>();
+    multiply(&columns, matrix, size, false, 20 - BIT_DEPTH)
+        .into_iter()
+        .map(|value| (value + 128).clamp(0, 255) as u8)
+        .collect()
+}
+
+pub fn quantise(coefficients: &[i32], qp: u8, size: usize) -> Vec<i32> {
+    let transform_shift = MAX_TRANSFORM_RANGE - BIT_DEPTH - size.ilog2();
+    let shift = 14 + qp as u32 / 6 + transform_shift;
+    // Rounding offset of 1/3 of a step, as the HEVC reference encoder uses for intra blocks
+    let offset = 171i64 << (shift - 9);
+    let scale = QUANT_SCALES[qp as usize % 6] as i64;
+    coefficients
+        .iter()
+        .map(|&coefficient| {
+            let level = ((coefficient.unsigned_abs() as i64 * scale + offset) >> shift) as i32;
+            clip_16(level * coefficient.signum())
+        })
+        .collect()
+}
+
+pub fn dequantise(levels: &[i32], qp: u8, size: usize) -> Vec<i32> {
+    // A flat scaling list, in which every entry is 16
+    let shift = BIT_DEPTH + size.ilog2() + 10 - MAX_TRANSFORM_RANGE;
+    let scale = (16 * LEVEL_SCALES[qp as usize % 6] as i64) << (qp / 6);
+    levels
+        .iter()
+        .map(|&level| clip_16(((level as i64 * scale + (1 << (shift - 1))) >> shift) as i32))
+        .collect()
+}
+
+// Product of two size x size matrices with rounding, shifted right. The second is transposed
+// if `transpose` is set
+fn multiply(a: &[i32], b: &[i32], size: usize, transpose: bool, shift: u32) -> Vec<i32> {
+    let mut product = vec![0; size * size];
+    for row in 0..size {
+        for column in 0..size {
+            let sum: i32 = (0..size)
+                .map(|k| {
+                    let b_value = match transpose {
+                        true => b[column * size + k],
+                        false => b[k * size + column],
+                    };
+                    a[row * size + k] * b_value
+                })
+                .sum();
+            product[row * size + column] = round_shift(sum, shift);
+        }
+    }
+    product
+}
+
+// Product of the transpose of `a` with `b`
+fn multiply_transposed(a: &[i32], b: &[i32], size: usize, shift: u32) -> Vec<i32> {
+    let mut product = vec![0; size * size];
+    for row in 0..size {
+        for column in 0..size {
+            let sum: i32 = (0..size)
+                .map(|k| a[k * size + row] * b[k * size + column])
+                .sum();
+            product[row * size + column] = round_shift(sum, shift);
+        }
+    }
+    product
+}
+
+fn round_shift(value: i32, shift: u32) -> i32 {
+    match shift {
+        0 => value,
+        _ => (value + (1 << (shift - 1))) >> shift,
+    }
+}
+
+fn clip_16(value: i32) -> i32 {
+    value.clamp(i16::MIN as i32, i16::MAX as i32)
+}
+
+#[test]
+fn builds_hevc_matrices() {
+    let matrix = transform_matrix(4);
+    assert_eq!(
+        matrix,
+        [64, 64, 64, 64, 83, 36, -36, -83, 64, -64, -64, 64, 36, -83, 83, -36]
+    );
+    assert_eq!(
+        transform_matrix(8)[8..16],
+        [89, 75, 50, 18, -18, -50, -75, -89]
+    );
+    assert_eq!(
+        transform_matrix(32)[32..40],
+        [90, 90, 88, 85, 82, 78, 73, 67]
+    );
+    assert_eq!(
+        transform_matrix(32)[31 * 32..31 * 32 + 4],
+        [4, -13, 22, -31]
+    );
+
+    // Rows are close to orthogonal, with norms close to 64·√size
+    for size in dct_2d::BLOCK_SIZES {
+        let matrix = transform_matrix(size);
+        for a in 0..size {
+            for b in 0..size {
+                let dot: i32 = (0..size)
+                    .map(|n| matrix[a * size + n] * matrix[b * size + n])
+                    .sum();
+                let expected = if a == b { 4096 * size as i32 } else { 0 };
+                assert!(
+                    (dot - expected).abs() <= 16 * size as i32,
+                    "{size}: {a} {b} {dot}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn matches_floating_point_quantisation() {
+    use crate::{
+        dct_2d::{QuantMatrices, QuantPreset},
+        generate::{generate_frame, Pattern},
+        metrics::psnr,
+        yuv4mpeg2::{ColorSpace, Header},
+    };
+
+    let header = Header {
+        width: 72,
+        height: 40,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let frame = generate_frame(Pattern::ZonePlate, &header, 0);
+
+    // At QP 4 the step size is 1, so only rounding is lost
+    for block_size in dct_2d::BLOCK_SIZES {
+        let fine = quantise_frame(frame.clone(), 4, block_size, Padding::Replicate);
+        assert!(psnr(&frame.data_y, &fine.data_y) > 50.);
+    }
+
+    // QP 28 has a step size of 16, the same as the flat matrix. The rounding offset of a third
+    // of a step zeroes more coefficients than rounding to nearest, so it's a little worse
+    let integer = quantise_frame(frame.clone(), 28, 8, Padding::Replicate);
+    let float = dct_2d::quantise_frame(
+        frame.clone(),
+        &QuantMatrices::preset(QuantPreset::Flat),
+        8,
+        Padding::Replicate,
+    );
+    let (integer_psnr, float_psnr) = (
+        psnr(&frame.data_y, &integer.data_y),
+        psnr(&frame.data_y, &float.data_y),
+    );
+    dbg!(integer_psnr, float_psnr);
+    assert!(integer_psnr < float_psnr && integer_psnr > float_psnr - 2.);
+
+    // The arithmetic is exact, so any change to the output is a change to the codec
+    let digests = crate::metrics::plane_md5s(&integer).map(|digest| format!("{digest:x}"));
+    assert_eq!(
+        digests,
+        [
+            "2baf832ad102526487e25c73e25b6052",
+            "351fe76cc7da981447c8ac12fbb58980",
+            "351fe76cc7da981447c8ac12fbb58980"
+        ]
+    );
+}
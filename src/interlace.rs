@@ -0,0 +1,357 @@
+use crate::yuv4mpeg2::{Frame, Header, InterlaceMode};
+
+// Splits a frame into its two fields, returned as (top, bottom).
+// Each field is a frame of half the height holding alternate lines of every plane,
+// with the top field taking the even lines (so it gets the extra line for odd heights).
+// With vertically subsampled chroma the bottom field can hold one chroma line fewer than its
+// height implies (a 4:2:0 frame whose height is 2 more than a multiple of 4), so its last
+// chroma line is repeated to fill the field's chroma planes
+pub fn split_fields(frame: &Frame) -> (Frame, Frame) {
+    let (top_y, bottom_y) = split_plane(&frame.data_y, frame.width, frame.height);
+    let (top_cb, bottom_cb) =
+        split_plane(&frame.data_cb, frame.chroma_width(), frame.chroma_height());
+    let (top_cr, bottom_cr) =
+        split_plane(&frame.data_cr, frame.chroma_width(), frame.chroma_height());
+    let field = |data_y, data_cb: Vec<u8>, data_cr: Vec<u8>, height| {
+        let mut field = Frame {
+            width: frame.width,
+            height,
+            color_space: frame.color_space,
+            data_y,
+            data_cb: vec![],
+            data_cr: vec![],
+        };
+        let (chroma_width, chroma_height) = (field.chroma_width(), field.chroma_height());
+        field.data_cb = fit_rows(&data_cb, chroma_width, chroma_height);
+        field.data_cr = fit_rows(&data_cr, chroma_width, chroma_height);
+        field
+    };
+    (
+        field(top_y, top_cb, top_cr, frame.height.div_ceil(2)),
+        field(bottom_y, bottom_cb, bottom_cr, frame.height / 2),
+    )
+}
+
+// Interleaves a pair of fields produced by split_fields back into a frame of the given height,
+// dropping any lines that were repeated to pad the fields
+pub fn merge_fields(top: &Frame, bottom: &Frame, height: usize) -> Frame {
+    let mut frame = Frame {
+        width: top.width,
+        height,
+        color_space: top.color_space,
+        data_y: vec![],
+        data_cb: vec![],
+        data_cr: vec![],
+    };
+    frame.data_y = merge_plane(&top.data_y, &bottom.data_y, frame.width, height);
+    let (chroma_width, chroma_height) = (frame.chroma_width(), frame.chroma_height());
+    frame.data_cb = merge_plane(&top.data_cb, &bottom.data_cb, chroma_width, chroma_height);
+    frame.data_cr = merge_plane(&top.data_cr, &bottom.data_cr, chroma_width, chroma_height);
+    frame
+}
+
+// Applies a per-frame operation to each field separately and re-interleaves the result,
+// so that lines captured at different times are never transformed together
+pub fn map_fields(frame: &Frame, mut operation: impl FnMut(Frame) -> Frame) -> Frame {
+    let (top, bottom) = split_fields(frame);
+    merge_fields(&operation(top), &operation(bottom), frame.height)
+}
+
+// As map_fields, for an operation that can fail on either field
+pub fn try_map_fields<E>(
+    frame: &Frame,
+    mut operation: impl FnMut(Frame) -> Result<Frame, E>,
+) -> Result<Frame, E> {
+    let (top, bottom) = split_fields(frame);
+    Ok(merge_fields(&operation(top)?, &operation(bottom)?, frame.height))
+}
+
+fn split_plane(values: &[u8], width: usize, height: usize) -> (Vec<u8>, Vec<u8>) {
+    let mut top = Vec::with_capacity(height.div_ceil(2) * width);
+    let mut bottom = Vec::with_capacity(height / 2 * width);
+    for (row_index, row) in values.chunks_exact(width.max(1)).take(height).enumerate() {
+        if row_index % 2 == 0 {
+            top.extend_from_slice(row);
+        } else {
+            bottom.extend_from_slice(row);
+        }
+    }
+    (top, bottom)
+}
+
+// Truncates a plane to the given number of rows, or pads it by repeating its last row
+fn fit_rows(values: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let rows = values
+        .chunks_exact(width.max(1))
+        .take(height)
+        .collect::<Vec<_>>();
+    let last = rows.last().copied().unwrap_or(&[]);
+    let mut fitted = rows.concat();
+    for _ in rows.len()..height {
+        fitted.extend_from_slice(last);
+    }
+    fitted
+}
+
+fn merge_plane(top: &[u8], bottom: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut values = Vec::with_capacity(width * height);
+    let mut bottom_rows = bottom.chunks_exact(width.max(1));
+    for row in top.chunks_exact(width.max(1)) {
+        values.extend_from_slice(row);
+        if let Some(row) = bottom_rows.next() {
+            values.extend_from_slice(row);
+        }
+    }
+    values.truncate(width * height);
+    values
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DeinterlaceMode {
+    /// Interpolate each field up to a full frame, doubling the frame rate
+    Bob,
+    /// Keep both fields of each frame together
+    Weave,
+    /// Weave where the picture is static, edge-directed interpolation where it moves
+    Adaptive,
+}
+
+// Differences between frames below this are treated as noise rather than motion
+const MOTION_THRESHOLD: u8 = 12;
+
+// Converts interlaced frames into progressive frames
+pub struct Deinterlacer {
+    mode: DeinterlaceMode,
+    top_field_first: bool,
+    previous: Option<Frame>,
+}
+
+impl Deinterlacer {
+    pub fn new(mode: DeinterlaceMode, interlace_mode: InterlaceMode) -> Self {
+        Deinterlacer {
+            mode,
+            top_field_first: interlace_mode != InterlaceMode::Ib,
+            previous: None,
+        }
+    }
+
+    // Header of the progressive output, with the frame rate doubled when bobbing
+    pub fn output_header(&self, header: &Header) -> Header {
+        let mut output_header = *header;
+        output_header.interlace_mode = InterlaceMode::Ip;
+        if self.mode == DeinterlaceMode::Bob {
+            output_header.frame_rate_numerator *= 2;
+        }
+        output_header
+    }
+
+    // Returns the progressive frames (two for bob, otherwise one) for an interlaced frame
+    pub fn deinterlace(&mut self, frame: Frame) -> Vec<Frame> {
+        // Parity of the lines that make up the field captured first
+        let first_parity = if self.top_field_first { 0 } else { 1 };
+
+        match self.mode {
+            DeinterlaceMode::Bob => vec![
+                frame.map_planes(|_, values, width, height| {
+                    interpolate_field(values, width, height, first_parity)
+                }),
+                frame.map_planes(|_, values, width, height| {
+                    interpolate_field(values, width, height, 1 - first_parity)
+                }),
+            ],
+            DeinterlaceMode::Weave => vec![frame],
+            DeinterlaceMode::Adaptive => {
+                let output = match &self.previous {
+                    Some(previous) => {
+                        let previous_planes = previous.planes();
+                        frame.map_planes(|index, values, width, height| {
+                            motion_adaptive(
+                                values,
+                                previous_planes[index].0,
+                                width,
+                                height,
+                                first_parity,
+                            )
+                        })
+                    }
+                    None => frame.map_planes(|_, values, width, height| {
+                        interpolate_field(values, width, height, first_parity)
+                    }),
+                };
+                self.previous = Some(frame);
+                vec![output]
+            }
+        }
+    }
+}
+
+// Keeps the lines of the given parity and fills in the others by averaging the lines above and below
+fn interpolate_field(values: &[u8], width: usize, height: usize, parity: usize) -> Vec<u8> {
+    let mut output = values.to_vec();
+    for row in (0..height).filter(|row| row % 2 != parity) {
+        for x in 0..width {
+            output[row * width + x] = edge_directed_pixel(values, width, height, row, x, 0);
+        }
+    }
+    output
+}
+
+// Keeps the lines of the given parity. Each pixel of the other lines is woven in from the
+// current frame where it matches the previous frame, and otherwise interpolated along the
+// local edge direction from the lines above and below
+fn motion_adaptive(
+    values: &[u8],
+    previous: &[u8],
+    width: usize,
+    height: usize,
+    parity: usize,
+) -> Vec<u8> {
+    let mut output = values.to_vec();
+    for row in (0..height).filter(|row| row % 2 != parity) {
+        let (above, below) = neighbouring_rows(row, height);
+        for x in 0..width {
+            let motion = [row, above, below]
+                .iter()
+                .map(|&r| values[r * width + x].abs_diff(previous[r * width + x]))
+                .max()
+                .unwrap_or(0);
+            if motion >= MOTION_THRESHOLD {
+                output[row * width + x] = edge_directed_pixel(values, width, height, row, x, 1);
+            }
+        }
+    }
+    output
+}
+
+// Rows either side of a missing row, reflected back into the picture at the top and bottom
+fn neighbouring_rows(row: usize, height: usize) -> (usize, usize) {
+    let above = if row > 0 { row - 1 } else { (row + 1).min(height - 1) };
+    let below = if row + 1 < height { row + 1 } else { row.saturating_sub(1) };
+    (above, below)
+}
+
+// Edge-based line average: averages the pair of pixels above and below the missing pixel
+// along whichever direction (up to max_offset pixels either side) has the closest match
+fn edge_directed_pixel(
+    values: &[u8],
+    width: usize,
+    height: usize,
+    row: usize,
+    x: usize,
+    max_offset: isize,
+) -> u8 {
+    let (above, below) = neighbouring_rows(row, height);
+    let clamp_x = |x: isize| x.clamp(0, width as isize - 1) as usize;
+
+    let mut best = (u8::MAX, 0);
+    for offset in [0isize, -1, 1, -2, 2].into_iter().filter(|o| o.abs() <= max_offset) {
+        let a = values[above * width + clamp_x(x as isize + offset)];
+        let b = values[below * width + clamp_x(x as isize - offset)];
+        let difference = a.abs_diff(b);
+        if offset == 0 || difference < best.0 {
+            best = (difference, (a as u16 + b as u16).div_ceil(2) as u8);
+        }
+    }
+    best.1
+}
+
+#[test]
+fn splits_and_merges_fields() {
+    use crate::yuv4mpeg2::ColorSpace;
+
+    const WIDTH: usize = 4;
+    const HEIGHT: usize = 8;
+    // Alternate lines are fully combed: 0 on even lines, 255 on odd lines
+    let data_y = (0..HEIGHT * WIDTH)
+        .map(|i| {
+            if (i / WIDTH).is_multiple_of(2) {
+                0
+            } else {
+                255
+            }
+        })
+        .collect();
+    let frame = Frame {
+        width: WIDTH,
+        height: HEIGHT,
+        color_space: ColorSpace::C420,
+        data_y,
+        data_cb: (0..8).collect(),
+        data_cr: (8..16).collect(),
+    };
+
+    let (top, bottom) = split_fields(&frame);
+    assert_eq!(top.height, HEIGHT / 2);
+    assert!(top.data_y.iter().all(|&value| value == 0)); // each field is free of combing
+    assert!(bottom.data_y.iter().all(|&value| value == 255));
+    assert_eq!(top.data_cb, vec![0, 1, 4, 5]);
+
+    let merged = merge_fields(&top, &bottom, HEIGHT);
+    assert_eq!(merged.to_vec(), frame.to_vec());
+}
+
+#[test]
+fn splits_fields_with_odd_chroma_heights() {
+    use crate::yuv4mpeg2::ColorSpace;
+
+    // A 4:2:0 frame 10 lines high has 5 chroma lines, 3 of which belong to the top field, but
+    // each 5 line field has 3 chroma lines
+    let frame = Frame {
+        width: 16,
+        height: 10,
+        color_space: ColorSpace::C420jpeg,
+        data_y: (0..160).map(|i| i as u8).collect(),
+        data_cb: (0..40).collect(),
+        data_cr: (100..140).collect(),
+    };
+    let (top, bottom) = split_fields(&frame);
+    for field in [&top, &bottom] {
+        assert_eq!(field.data_y.len(), field.width * field.height);
+        let chroma_len = field.chroma_width() * field.chroma_height();
+        assert_eq!(field.data_cb.len(), chroma_len);
+        assert_eq!(field.data_cr.len(), chroma_len);
+    }
+    assert_eq!((top.height, bottom.height), (5, 5));
+    assert_eq!((top.chroma_height(), bottom.chroma_height()), (3, 3));
+    assert_eq!(top.data_cb[16..24], frame.data_cb[32..40]);
+    assert_eq!(bottom.data_cb[16..24], frame.data_cb[24..32]);
+
+    let merged = merge_fields(&top, &bottom, frame.height);
+    assert_eq!(merged.to_vec(), frame.to_vec());
+    assert_eq!(map_fields(&frame, |field| field).to_vec(), frame.to_vec());
+}
+
+#[test]
+fn deinterlaces_moving_and_static_fields() {
+    use crate::yuv4mpeg2::ColorSpace;
+
+    const WIDTH: usize = 8;
+    const HEIGHT: usize = 8;
+    // Top field is 100 and bottom field is 200, as if the scene brightened between fields
+    let frame = Frame {
+        width: WIDTH,
+        height: HEIGHT,
+        color_space: ColorSpace::C444,
+        data_y: (0..HEIGHT * WIDTH)
+            .map(|i| if (i / WIDTH).is_multiple_of(2) { 100 } else { 200 })
+            .collect(),
+        data_cb: vec![128; WIDTH * HEIGHT],
+        data_cr: vec![128; WIDTH * HEIGHT],
+    };
+
+    let mut bob = Deinterlacer::new(DeinterlaceMode::Bob, InterlaceMode::It);
+    let frames = bob.deinterlace(frame.clone());
+    assert_eq!(frames.len(), 2);
+    assert!(frames[0].data_y.iter().all(|&value| value == 100));
+    assert!(frames[1].data_y.iter().all(|&value| value == 200));
+
+    // Moving areas are interpolated from the first field, static areas are woven
+    let mut adaptive = Deinterlacer::new(DeinterlaceMode::Adaptive, InterlaceMode::It);
+    let mut dark_frame = frame.clone();
+    dark_frame.data_y = vec![0; WIDTH * HEIGHT];
+    adaptive.deinterlace(dark_frame);
+    let moving = adaptive.deinterlace(frame.clone());
+    assert!(moving[0].data_y.iter().all(|&value| value == 100));
+    let still = adaptive.deinterlace(frame.clone());
+    assert_eq!(still[0].data_y, frame.data_y);
+}
@@ -1,4 +1,16 @@
 pub mod yuv4mpeg2;
 pub mod dct_2d;
 pub mod dct_1d;
-pub mod dct_3d;
\ No newline at end of file
+pub mod dct_3d;
+pub mod interlace;
+pub mod telecine;
+pub mod spatial;
+pub mod padding;
+pub mod metrics;
+pub mod frame_rate;
+pub mod generate;
+pub mod partition;
+pub mod integer_dct;
+pub mod fixed_dct;
+pub mod wavelet;
+pub mod spiht;
@@ -1,79 +1,886 @@
 use std::{
+    cell::Cell,
     fs,
-    io::{BufReader, BufWriter},
-    path::PathBuf,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    rc::Rc,
 };
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use itertools::Itertools;
 
-use crate::{dct_2d::quantise_frame, dct_3d::quantise_chunk};
-mod dct_1d;
-mod dct_2d;
-mod dct_3d;
-mod yuv4mpeg2;
+use squish::{
+    dct_2d::{self, quantise_frame, QuantMatrices, QuantPreset},
+    dct_3d::{
+        self, quantise_chunk, quantise_cubes, TemporalMode, TemporalQuantiser, TemporalWeights,
+        Weights,
+    },
+    frame_rate::{self, FrameRate, FrameRateConverter, RateConversion},
+    generate::{self, Pattern},
+    integer_dct,
+    interlace::{self, DeinterlaceMode, Deinterlacer},
+    metrics,
+    padding::Padding,
+    partition,
+    spatial::{self, Rect, ScaleFilter, Size},
+    spiht,
+    telecine::{self, InverseTelecine},
+    wavelet::{self, Wavelet},
+    yuv4mpeg2::{
+        self, decode::Y4MReader, encode::Y4MWriter, mmap::MappedReader, ColorSpace, Frame, Header,
+        InterlaceMode, Limits, PixelAspectRatio,
+    },
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+
+    #[command(flatten)]
+    limits: LimitArgs,
+}
+
+// Bounds on the input files that are accepted, which apply to every command
+#[derive(clap::Args, Debug)]
+struct LimitArgs {
+    /// Largest frame width accepted from an input file
+    #[arg(long, global = true, default_value_t = Limits::default().max_width)]
+    max_width: usize,
+
+    /// Largest frame height accepted from an input file
+    #[arg(long, global = true, default_value_t = Limits::default().max_height)]
+    max_height: usize,
+
+    /// Largest frame, in bytes, accepted from an input file
+    #[arg(long, global = true, default_value_t = Limits::default().max_frame_bytes)]
+    max_frame_bytes: usize,
+
+    /// Longest stream or frame header line, in bytes, accepted from an input file
+    #[arg(long, global = true, default_value_t = Limits::default().max_header_length)]
+    max_header_bytes: usize,
+}
+
+impl LimitArgs {
+    fn limits(&self) -> Limits {
+        Limits {
+            max_width: self.max_width,
+            max_height: self.max_height,
+            max_frame_bytes: self.max_frame_bytes,
+            max_header_length: self.max_header_bytes,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Copy a range of frames into a new file
+    Trim {
+        /// Input file (must be in YUV4MPEG2 format)
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Output file (will be in YUV4MPEG2 format)
+        #[arg(short, long, default_value = "output.y4m")]
+        output_file: PathBuf,
+
+        /// Index of the first frame to keep
+        #[arg(long, default_value_t = 0)]
+        start: usize,
+
+        /// Number of frames to keep (defaults to all remaining frames)
+        #[arg(long)]
+        count: Option<usize>,
+    },
+    /// Split a file into pieces with a fixed number of frames
+    Split {
+        /// Input file (must be in YUV4MPEG2 format)
+        #[arg(short, long)]
+        input_file: PathBuf,
+
+        /// Output file name, to which the index of each piece is appended
+        #[arg(short, long, default_value = "output.y4m")]
+        output_file: PathBuf,
+
+        /// Number of frames in each piece
+        #[arg(long)]
+        every: usize,
+    },
+    /// Join files with the same dimensions and color space one after another
+    Concat {
+        /// Input files (must be in YUV4MPEG2 format)
+        #[arg(required = true)]
+        input_files: Vec<PathBuf>,
+
+        /// Output file (will be in YUV4MPEG2 format)
+        #[arg(short, long, default_value = "output.y4m")]
+        output_file: PathBuf,
+    },
+    /// Print the MD5 checksum of every plane of every frame
+    Hash {
+        /// Input file (must be in YUV4MPEG2 format)
+        input_file: PathBuf,
+
+        /// Hash the planes straight from the file mapped into memory, without copying them
+        #[arg(long, default_value_t = false)]
+        mmap: bool,
+    },
+    /// Compare two files and report the first sample that differs
+    Diff {
+        /// Input files (must be in YUV4MPEG2 format)
+        first_file: PathBuf,
+        second_file: PathBuf,
+    },
+    /// Write a synthetic test pattern
+    Generate {
+        /// Output file (will be in YUV4MPEG2 format)
+        #[arg(short, long, default_value = "output.y4m")]
+        output_file: PathBuf,
+
+        #[arg(short, long, value_enum)]
+        pattern: Pattern,
+
+        #[arg(long, default_value_t = 352)]
+        width: usize,
+
+        #[arg(long, default_value_t = 288)]
+        height: usize,
+
+        /// Number of frames to write
+        #[arg(long, default_value_t = 64)]
+        frames: usize,
+
+        /// Frame rate, given as NUMERATOR:DENOMINATOR or frames per second
+        #[arg(long, default_value = "25:1")]
+        frame_rate: FrameRate,
+
+        /// Color space in header notation, e.g. C420jpeg, C422, C444 or Cmono
+        #[arg(long, default_value = "C420jpeg")]
+        color_space: ColorSpace,
+    },
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
     /// Input file (must be in YUV4MPEG2 format)
-    #[arg(short, long)]
-    input_file: PathBuf,
+    #[arg(short, long, required = true)]
+    input_file: Option<PathBuf>,
 
     /// Output file (will be in YUV4MPEG2 format)
     #[arg(short, long, default_value = "output.y4m")]
     output_file: PathBuf,
 
+    /// Read the input by mapping it into memory rather than streaming it. Each frame is still
+    /// copied out of the map, as the rest of the pipeline works on owned frames
+    #[arg(long, default_value_t = false)]
+    mmap: bool,
+
     /// Quantisation factor (higher results in lower quality)
     #[arg(short, long, default_value_t = 1.)]
     quantisation_factor: f64,
 
-    /// Enable dct and transform across the time domain in chunks of 8 frames
+    /// Quantisation factor for the chroma planes in 2D and cube modes (defaults to the
+    /// quantisation factor)
+    #[arg(long)]
+    chroma_quantisation_factor: Option<f64>,
+
+    /// JPEG quality from 1 to 100, scaling the tables the same way as libjpeg (2D and cube modes).
+    /// Used instead of the quantisation factors
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    #[arg(conflicts_with_all = ["quantisation_factor", "chroma_quantisation_factor"])]
+    quality: Option<u8>,
+
+    /// Base quantisation matrices in 2D and cube modes, which the quality or quantisation
+    /// factors scale
+    #[arg(long, value_enum, default_value_t = QuantPreset::AnnexK)]
+    quant_preset: QuantPreset,
+
+    /// File of custom luma and chroma quantisation matrices to use instead of a preset
+    #[arg(long, conflicts_with = "quant_preset")]
+    quant_matrix_file: Option<PathBuf>,
+
+    /// Width and height of the blocks of the spatial transform in 2D and cube modes: 4, 8, 16
+    /// or 32. The quantisation matrices are resampled to match
+    #[arg(long, default_value_t = 8)]
+    block_size: usize,
+
+    /// Use an exact integer transform and flat quantiser in the style of HEVC with this
+    /// quantisation parameter (0 to 51), so the output is identical on every platform (2D mode).
+    /// The step size doubles every 6 steps and is 16 at 28
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=integer_dct::MAX_QP as i64))]
+    #[arg(conflicts_with_all = ["quantisation_factor", "chroma_quantisation_factor", "quality"])]
+    #[arg(conflicts_with_all = ["quant_preset", "quant_matrix_file", "temporal_quantisation"])]
+    qp: Option<u8>,
+
+    /// Quantise in the wavelet domain instead of in blocks (2D mode). Each subband's step is
+    /// 16 times the quantisation factor, weighted by the subband's gain. A factor of 0 with the
+    /// reversible le-gall53 wavelet is lossless
+    #[arg(long, value_enum)]
+    #[arg(conflicts_with_all = ["quality", "quant_preset", "quant_matrix_file", "block_size"])]
+    #[arg(conflicts_with_all = ["qp", "region_size", "temporal_quantisation"])]
+    wavelet: Option<Wavelet>,
+
+    /// Number of levels of the wavelet decomposition
+    #[arg(long, default_value_t = 5, requires = "wavelet")]
+    wavelet_levels: usize,
+
+    /// Code the wavelet coefficients with an embedded SPIHT bit-plane coder, truncating the
+    /// stream of each frame (or field, when interlaced) at this many bytes. Used instead of the
+    /// quantisation factors
+    #[arg(long, requires = "wavelet")]
+    #[arg(conflicts_with_all = ["quantisation_factor", "chroma_quantisation_factor"])]
+    spiht_bytes: Option<usize>,
+
+    /// Divide each square region of this size (32 or 64) into a quadtree of transform blocks,
+    /// choosing the splits that minimise distortion + λ·estimated bits (2D mode)
+    #[arg(long, conflicts_with_all = ["block_size", "temporal_quantisation", "qp"])]
+    region_size: Option<usize>,
+
+    /// Lagrange multiplier λ weighing estimated bits against squared error when choosing
+    /// partitions (defaults to a value derived from the luma quantisation matrix)
+    #[arg(long, requires = "region_size")]
+    rd_lambda: Option<f64>,
+
+    /// Text file to write the partition split flags of every plane of every frame to
+    #[arg(long, requires = "region_size")]
+    partition_file: Option<PathBuf>,
+
+    /// YUV4MPEG2 file to write the output to with the chosen partitions drawn on the luma plane
+    #[arg(long, requires = "region_size")]
+    partition_visualisation: Option<PathBuf>,
+
+    /// Enable dct and transform across the time domain in chunks of frames, padding a shorter
+    /// final chunk
     #[arg(short, long, default_value_t = false)]
     temporal_quantisation: bool,
+
+    /// Number of frames in each chunk in temporal mode
+    #[arg(long, default_value_t = 8)]
+    chunk_length: usize,
+
+    /// Overlap chunks by half their length in temporal mode, cross-fading between them to
+    /// avoid a pulse in quality every chunk
+    #[arg(long, default_value_t = false)]
+    overlap: bool,
+
+    /// Transform used in temporal mode
+    #[arg(long, value_enum, default_value_t = TemporalMode::Time)]
+    temporal_mode: TemporalMode,
+
+    /// Multipliers of the quantisation factor for each of the 8 temporal frequencies of luma in
+    /// temporal mode, from static to the fastest flicker, given as comma-separated numbers
+    #[arg(long, default_value_t = dct_3d::DEFAULT_LUMA_WEIGHTS)]
+    temporal_weights: Weights,
+
+    /// Multipliers of the quantisation factor for each temporal frequency of chroma in temporal mode
+    #[arg(long, default_value_t = dct_3d::DEFAULT_CHROMA_WEIGHTS)]
+    temporal_chroma_weights: Weights,
+
+    /// How to fill the part of an edge block that lies outside the picture, and the frames
+    /// after the end of a short final chunk in temporal mode
+    #[arg(long, value_enum, default_value_t = Padding::Replicate)]
+    padding: Padding,
+
+    /// Detect and remove 3:2 pulldown, restoring the original film frame rate
+    #[arg(long, default_value_t = false)]
+    inverse_telecine: bool,
+
+    /// Convert interlaced input to progressive frames before quantisation. Input whose header
+    /// marks it as progressive is left as it is
+    #[arg(long, value_enum)]
+    deinterlace: Option<DeinterlaceMode>,
+
+    /// Crop each frame to a rectangle given as WIDTHxHEIGHT+X+Y
+    #[arg(long)]
+    crop: Option<Rect>,
+
+    /// Scale each frame to a size given as WIDTHxHEIGHT
+    #[arg(long)]
+    scale: Option<Size>,
+
+    /// Filter used when scaling
+    #[arg(long, value_enum, default_value_t = ScaleFilter::Bicubic)]
+    scale_filter: ScaleFilter,
+
+    /// Pad each frame to a multiple of this size by replicating its right and bottom edges
+    #[arg(long)]
+    pad_to: Option<usize>,
+
+    /// Convert to a new frame rate, given as NUMERATOR:DENOMINATOR or frames per second
+    #[arg(long)]
+    frame_rate: Option<FrameRate>,
+
+    /// How frames are resampled in time when converting the frame rate
+    #[arg(long, value_enum, default_value_t = RateConversion::Nearest)]
+    rate_conversion: RateConversion,
 }
 
-fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
+fn main() -> Result<ExitCode, anyhow::Error> {
+    let cli = Cli::parse();
+    let limits = cli.limits.limits();
 
-    // Accept input either from stdin, or a filepath as first argument
-    let mut reader = BufReader::new(
-        fs::File::open(args.input_file).context("Failed to open file. Check that it exists.")?,
-    );
+    match cli.command {
+        None => compress(cli.args, limits),
+        Some(Command::Trim {
+            input_file,
+            output_file,
+            start,
+            count,
+        }) => trim(&input_file, &output_file, start, count, limits),
+        Some(Command::Split {
+            input_file,
+            output_file,
+            every,
+        }) => split(&input_file, &output_file, every, limits),
+        Some(Command::Concat {
+            input_files,
+            output_file,
+        }) => concat(&input_files, &output_file, limits),
+        Some(Command::Hash { input_file, mmap }) => {
+            hash(&input_file, mmap, limits, &mut io::stdout().lock())
+        }
+        Some(Command::Diff {
+            first_file,
+            second_file,
+        }) => {
+            // Differing files aren't an error, but still give a failing exit status, as diff does
+            return match diff(&first_file, &second_file, limits)? {
+                Comparison::Identical(frames) => {
+                    println!("Files are identical ({frames} frames)");
+                    Ok(ExitCode::SUCCESS)
+                }
+                Comparison::Different(difference) => {
+                    println!("{difference}");
+                    Ok(ExitCode::FAILURE)
+                }
+            };
+        }
+        Some(Command::Generate {
+            output_file,
+            pattern,
+            width,
+            height,
+            frames,
+            frame_rate,
+            color_space,
+        }) => {
+            let header = Header {
+                width,
+                height,
+                frame_rate_numerator: frame_rate.numerator,
+                frame_rate_denominator: frame_rate.denominator,
+                interlace_mode: InterlaceMode::Ip,
+                pixel_aspect_ratio: PixelAspectRatio::Square,
+                color_space,
+            };
+            generate(&output_file, pattern, &header, frames)
+        }
+    }
+    .map(|()| ExitCode::SUCCESS)
+}
+
+fn open_input(path: &Path, limits: Limits) -> Result<Y4MReader<fs::File>, anyhow::Error> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Failed to open {}. Check that it exists.", path.display()))?;
+    let decoder = yuv4mpeg2::Decoder::new(file).with_limits(limits);
+    decoder
+        .read_header()
+        .with_context(|| format!("Failed to read header of {}", path.display()))
+}
 
-    let decoder = yuv4mpeg2::Decoder::new(&mut reader);
-    let reader = decoder.read_header().context("Failed to read header")?;
+// Context for an error decoding one of the frames of a file
+fn frame_context(path: &Path, index: usize) -> String {
+    format!("Failed to read frame {index} of {}", path.display())
+}
 
-    // Output either to stdout, or a filepath as second argument if given
-    let writer = BufWriter::new(
-        fs::File::create(args.output_file)
-            .context("Failed to create file. Check that the target directory exists.")?,
-    );
+fn create_output(path: &Path, header: &Header) -> Result<Y4MWriter<fs::File>, anyhow::Error> {
+    let writer = fs::File::create(path)
+        .context("Failed to create file. Check that the target directory exists.")?;
     let encoder = yuv4mpeg2::Encoder::new(writer);
-    let mut writer = encoder
-        .write_header(&reader.header)
-        .context("Failed to write header")?;
+    encoder
+        .write_header(header)
+        .context("Failed to write header")
+}
+
+fn trim(
+    input_file: &Path,
+    output_file: &Path,
+    start: usize,
+    count: Option<usize>,
+    limits: Limits,
+) -> Result<(), anyhow::Error> {
+    let reader = open_input(input_file, limits)?;
+    let mut writer = create_output(output_file, &reader.header)?;
+
+    // Frames before the range are still decoded, so that errors in them are reported
+    let end = count.map_or(usize::MAX, |count| start.saturating_add(count));
+    let mut frame_count = 0;
+    for (index, frame) in reader.into_iter().enumerate().take(end) {
+        let frame = frame.with_context(|| frame_context(input_file, index))?;
+        if index >= start {
+            writer.write_frame(frame).context("Failed to write frame")?;
+            frame_count += 1;
+        }
+    }
+
+    println!("Wrote {} frames", frame_count);
+    Ok(())
+}
+
+fn split(
+    input_file: &Path,
+    output_file: &Path,
+    every: usize,
+    limits: Limits,
+) -> Result<(), anyhow::Error> {
+    anyhow::ensure!(every > 0, "Each piece must contain at least one frame");
+    let reader = open_input(input_file, limits)?;
+    let header = reader.header;
+
+    // Pieces are named like output_000.y4m, output_001.y4m, ...
+    let stem = output_file.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = output_file.extension().unwrap_or("y4m".as_ref()).to_string_lossy();
+
+    let mut piece_count = 0;
+    for chunk in &reader.into_iter().enumerate().chunks(every) {
+        let piece_file = output_file.with_file_name(format!("{stem}_{piece_count:03}.{extension}"));
+        let mut writer = create_output(&piece_file, &header)?;
+        for (index, frame) in chunk {
+            let frame = frame.with_context(|| frame_context(input_file, index))?;
+            writer.write_frame(frame).context("Failed to write frame")?;
+        }
+        piece_count += 1;
+    }
+
+    println!("Wrote {} files", piece_count);
+    Ok(())
+}
+
+fn concat(
+    input_files: &[PathBuf],
+    output_file: &Path,
+    limits: Limits,
+) -> Result<(), anyhow::Error> {
+    let readers = input_files
+        .iter()
+        .map(|path| open_input(path, limits))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Check all the inputs up front so no output is written for incompatible files
+    let header = readers[0].header;
+    for (reader, path) in readers.iter().zip(input_files).skip(1) {
+        header.check_compatible(&reader.header).with_context(|| {
+            format!(
+                "{} can't be joined to {}",
+                path.display(),
+                input_files[0].display()
+            )
+        })?;
+    }
+
+    let mut writer = create_output(output_file, &header)?;
+    let mut frame_count = 0;
+    for (reader, path) in readers.into_iter().zip(input_files) {
+        for (index, frame) in reader.into_iter().enumerate() {
+            let frame = frame.with_context(|| frame_context(path, index))?;
+            writer.write_frame(frame).context("Failed to write frame")?;
+            frame_count += 1;
+        }
+    }
+
+    println!("Wrote {} frames", frame_count);
+    Ok(())
+}
+
+fn hash(
+    input_file: &Path,
+    mmap: bool,
+    limits: Limits,
+    output: &mut impl Write,
+) -> Result<(), anyhow::Error> {
+    // One line per frame, in a form that can be compared with standard text tools
+    let mut print_digests = |index: usize, planes: [&[u8]; 3]| {
+        let columns = metrics::PLANE_NAMES
+            .iter()
+            .zip(planes.map(md5::compute))
+            .map(|(name, digest)| format!("{name}={digest:x}"))
+            .join(" ");
+        writeln!(output, "{index} {columns}").context("Failed to write checksums")
+    };
+
+    if mmap {
+        let mapped = MappedReader::open_with_limits(input_file, limits)
+            .with_context(|| format!("Failed to map {}", input_file.display()))?;
+        for (index, frame) in mapped.frames().enumerate() {
+            print_digests(index, frame.planes().map(|(values, _, _)| values))?;
+        }
+    } else {
+        let reader = open_input(input_file, limits)?;
+        for (index, frame) in reader.into_iter().enumerate() {
+            let frame = frame.with_context(|| frame_context(input_file, index))?;
+            print_digests(index, frame.planes().map(|(values, _, _)| values))?;
+        }
+    }
+    Ok(())
+}
+
+// Outcome of comparing two files
+enum Comparison {
+    // Number of frames in each file
+    Identical(usize),
+    // Description of the first difference
+    Different(String),
+}
+
+fn diff(
+    first_file: &Path,
+    second_file: &Path,
+    limits: Limits,
+) -> Result<Comparison, anyhow::Error> {
+    let first = open_input(first_file, limits)?;
+    let second = open_input(second_file, limits)?;
+    first
+        .header
+        .check_compatible(&second.header)
+        .context("Files can't be compared")?;
+
+    let mut first_frames = first.into_iter();
+    let mut second_frames = second.into_iter();
+    let mut index = 0;
+    loop {
+        let first_frame = first_frames.next().transpose();
+        let first_frame = first_frame.with_context(|| frame_context(first_file, index))?;
+        let second_frame = second_frames.next().transpose();
+        let second_frame = second_frame.with_context(|| frame_context(second_file, index))?;
+        match (first_frame, second_frame) {
+            (Some(a), Some(b)) => {
+                if let Some(difference) = metrics::first_difference(&a, &b) {
+                    return Ok(Comparison::Different(format!(
+                        "Frame {index}, plane {}, pixel ({}, {}): {} != {}",
+                        metrics::PLANE_NAMES[difference.plane],
+                        difference.x,
+                        difference.y,
+                        difference.first,
+                        difference.second
+                    )));
+                }
+            }
+            (None, None) => return Ok(Comparison::Identical(index)),
+            (a, _) => {
+                let (shorter, longer) = match a {
+                    Some(_) => (second_file, first_file),
+                    None => (first_file, second_file),
+                };
+                return Ok(Comparison::Different(format!(
+                    "{} ends after {index} frames, but {} has more",
+                    shorter.display(),
+                    longer.display()
+                )));
+            }
+        }
+        index += 1;
+    }
+}
+
+fn generate(
+    output_file: &Path,
+    pattern: Pattern,
+    header: &Header,
+    frames: usize,
+) -> Result<(), anyhow::Error> {
+    let mut writer = create_output(output_file, header)?;
+    for index in 0..frames {
+        let frame = generate::generate_frame(pattern, header, index);
+        writer.write_frame(frame).context("Failed to write frame")?;
+    }
+
+    println!("Wrote {} frames", frames);
+    Ok(())
+}
+
+// Ends the frames at the first error, which is kept in `error` for the caller to report
+fn take_until_error<'a, E: 'a>(
+    frames: impl Iterator<Item = Result<Frame, E>> + 'a,
+    error: &Rc<Cell<Option<E>>>,
+) -> Box<dyn Iterator<Item = Frame> + 'a> {
+    let error = Rc::clone(error);
+    Box::new(frames.map_while(move |frame| {
+        frame
+            .map_err(|frame_error| error.set(Some(frame_error)))
+            .ok()
+    }))
+}
+
+// Applies a fallible operation to every frame, ending the frames at the first error, which is
+// kept in `error` for the caller to report
+fn try_map_frames<'a>(
+    frames: Box<dyn Iterator<Item = Frame> + 'a>,
+    error: &Rc<Cell<Option<spatial::Error>>>,
+    operation: impl FnMut(Frame) -> Result<Frame, spatial::Error> + 'a,
+) -> Box<dyn Iterator<Item = Frame> + 'a> {
+    take_until_error(frames.map(operation), error)
+}
+
+fn compress(args: Args, limits: Limits) -> Result<(), anyhow::Error> {
+    // Only absent when a subcommand is given, in which case this isn't called
+    let input_file = args.input_file.context("No input file given")?;
+    anyhow::ensure!(
+        dct_2d::BLOCK_SIZES.contains(&args.block_size),
+        "Block size must be one of {:?}",
+        dct_2d::BLOCK_SIZES
+    );
+    // A frame that can't be decoded ends the input, and is reported after the frames before it
+    // have been written
+    let read_error = Rc::new(Cell::new(None));
+    let mapped;
+    let (mut header, mut frames): (Header, Box<dyn Iterator<Item = Frame> + '_>) = if args.mmap {
+        mapped = MappedReader::open_with_limits(&input_file, limits)
+            .with_context(|| format!("Failed to map {}", input_file.display()))?;
+        // Quantisation writes new frames, so a copy is needed here whichever reader is used
+        (mapped.header, Box::new(mapped.frames().map(|frame| frame.to_frame())))
+    } else {
+        let reader = open_input(&input_file, limits)?;
+        (reader.header, take_until_error(reader.into_iter(), &read_error))
+    };
+
+    if args.inverse_telecine {
+        // The cadence is found before any frame is written, as it decides the output frame rate
+        let probe = frames
+            .by_ref()
+            .take(telecine::PROBE_FRAMES)
+            .collect::<Vec<_>>();
+        let phase = telecine::detect_phase(&probe, header.interlace_mode).with_context(|| {
+            format!(
+                "No 3:2 pulldown cadence found in the first {} frames, so the input doesn't look telecined",
+                telecine::PROBE_FRAMES
+            )
+        })?;
+        let source = probe.into_iter().chain(frames);
+        frames = Box::new(InverseTelecine::new(
+            source,
+            header.interlace_mode,
+            Some(phase),
+        ));
+        header = telecine::output_header(&header);
+    }
+
+    if let Some(mode) = args.deinterlace {
+        // Bobbing or blending progressive frames would only blur them
+        if header.interlace_mode.is_interlaced() {
+            let mut deinterlacer = Deinterlacer::new(mode, header.interlace_mode);
+            header = deinterlacer.output_header(&header);
+            frames = Box::new(frames.flat_map(move |frame| deinterlacer.deinterlace(frame)));
+        } else {
+            eprintln!("Warning: the input isn't interlaced, so it won't be deinterlaced");
+        }
+    }
+
+    // The first error from a spatial filter stops the frames, and is reported after the frames
+    // before it have been written
+    let spatial_error = Rc::new(Cell::new(None));
+    if let Some(rect) = args.crop {
+        spatial::check_crop(header.width, header.height, header.color_space, rect)
+            .context("Invalid crop rectangle")?;
+        header.width = rect.width;
+        header.height = rect.height;
+        frames = try_map_frames(frames, &spatial_error, move |frame| {
+            spatial::crop(&frame, rect)
+        });
+    }
+
+    if let Some(size) = args.scale {
+        anyhow::ensure!(
+            header.width > 0 && header.height > 0,
+            "Empty frames can't be scaled"
+        );
+        header.width = size.width;
+        header.height = size.height;
+        let filter = args.scale_filter;
+        frames = try_map_frames(frames, &spatial_error, move |frame| {
+            spatial::scale(&frame, size, filter)
+        });
+    }
+
+    if let Some(multiple) = args.pad_to {
+        let size = spatial::padded_size(header.width, header.height, multiple)
+            .context("Invalid padding")?;
+        header.width = size.width;
+        header.height = size.height;
+        frames = try_map_frames(frames, &spatial_error, move |frame| {
+            spatial::pad(&frame, multiple)
+        });
+    }
+
+    if let Some(rate) = args.frame_rate {
+        anyhow::ensure!(
+            header.frame_rate_numerator > 0 && header.frame_rate_denominator > 0,
+            "Input frame rate is unknown, so it can't be converted"
+        );
+        frames = Box::new(FrameRateConverter::new(frames, &header, rate, args.rate_conversion));
+        header = frame_rate::output_header(&header, rate);
+    }
+
+    let mut writer = create_output(&args.output_file, &header)?;
+
+    // Spatial quantisation tables, used by the 2D and cube modes
+    let base_matrices = match &args.quant_matrix_file {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .parse()
+            .with_context(|| format!("Failed to read matrices from {}", path.display()))?,
+        None => QuantMatrices::preset(args.quant_preset),
+    };
+    let quant_matrices = match args.quality {
+        Some(quality) => base_matrices.with_quality(quality),
+        None => base_matrices.scaled(
+            args.quantisation_factor,
+            args.chroma_quantisation_factor
+                .unwrap_or(args.quantisation_factor),
+        ),
+    };
 
     // Quantise all frames and write them out to a new file
     let mut frame_count = 0;
     if args.temporal_quantisation {
-        for chunk in &reader.into_iter().chunks(8) {
-            let frames = chunk.collect_vec();
-            if frames.len() == 8 { // ignore smaller chunk at end since fast dct works on length 8 arrays
-                let quantised_chunk = quantise_chunk(frames, args.quantisation_factor);
-                for frame in quantised_chunk {
-                    writer.write_frame(frame).context("Failed to write frame")?;
-                    frame_count += 1;
-                    // if frame_count >= 20 {
-                    //     break;
-                    // }
+        anyhow::ensure!(
+            args.chunk_length > 0,
+            "Chunks must contain at least one frame"
+        );
+        let temporal_weights = TemporalWeights {
+            luma: args.temporal_weights,
+            chroma: args.temporal_chroma_weights,
+        };
+        let quantiser = TemporalQuantiser::new(
+            frames,
+            args.chunk_length,
+            args.overlap,
+            args.padding,
+            |chunk| match args.temporal_mode {
+                TemporalMode::Time => {
+                    quantise_chunk(chunk, args.quantisation_factor, &temporal_weights)
+                }
+                TemporalMode::Cube => quantise_cubes(
+                    chunk,
+                    &quant_matrices,
+                    &temporal_weights,
+                    args.block_size,
+                    args.padding,
+                ),
+            },
+        );
+        for frame in quantiser {
+            writer.write_frame(frame).context("Failed to write frame")?;
+            frame_count += 1;
+        }
+    } else if let Some(region_size) = args.region_size {
+        anyhow::ensure!(
+            partition::REGION_SIZES.contains(&region_size),
+            "Region size must be one of {:?}",
+            partition::REGION_SIZES
+        );
+        // The partition file and visualisation describe whole frames, which would mix the two
+        // fields of interlaced content together
+        anyhow::ensure!(
+            !header.interlace_mode.is_interlaced(),
+            "Quadtree partitioning doesn't support interlaced input"
+        );
+        let lambda = args
+            .rd_lambda
+            .unwrap_or_else(|| partition::default_lambda(&quant_matrices));
+        let mut partition_file = match &args.partition_file {
+            Some(path) => {
+                let file = fs::File::create(path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                let mut file = io::BufWriter::new(file);
+                writeln!(
+                    file,
+                    "# region size {region_size}, split flags in depth-first order"
+                )?;
+                Some(file)
+            }
+            None => None,
+        };
+        let mut visualisation = match &args.partition_visualisation {
+            Some(path) => Some(create_output(path, &header)?),
+            None => None,
+        };
+
+        for frame in frames {
+            let (new_frame, partitions) = partition::quantise_frame(
+                frame,
+                &quant_matrices,
+                region_size,
+                lambda,
+                args.padding,
+            );
+            if let Some(file) = &mut partition_file {
+                for (name, plane_partitions) in metrics::PLANE_NAMES.iter().zip(&partitions) {
+                    let flags = partition::flags(plane_partitions, region_size);
+                    writeln!(file, "{frame_count} {name} {flags}")?;
                 }
             }
+            if let Some(writer) = &mut visualisation {
+                let drawn = partition::draw_partitions(&new_frame, &partitions[0], region_size);
+                writer.write_frame(drawn).context("Failed to write frame")?;
+            }
+            writer
+                .write_frame(new_frame)
+                .context("Failed to write frame")?;
+            frame_count += 1;
+        }
+        if let Some(file) = &mut partition_file {
+            file.flush().context("Failed to write partition file")?;
         }
     } else {
-        for frame in reader {
-            let new_frame = quantise_frame(frame, args.quantisation_factor);
+        // Interlaced fields are quantised separately so combing isn't mistaken for detail
+        let interlaced = header.interlace_mode.is_interlaced();
+        anyhow::ensure!(
+            args.wavelet_levels <= wavelet::MAX_LEVELS,
+            "At most {} wavelet levels are supported",
+            wavelet::MAX_LEVELS
+        );
+        let chroma_factor = args
+            .chroma_quantisation_factor
+            .unwrap_or(args.quantisation_factor);
+        // Only SPIHT coding can fail, when a plane doesn't match the frame's dimensions
+        let quantise = |frame| match (args.qp, args.wavelet, args.spiht_bytes) {
+            (Some(qp), _, _) => Ok(integer_dct::quantise_frame(
+                frame,
+                qp,
+                args.block_size,
+                args.padding,
+            )),
+            (None, Some(wavelet), Some(bytes)) => {
+                spiht::quantise_frame(frame, wavelet, args.wavelet_levels, bytes)
+            }
+            (None, Some(wavelet), None) => Ok(wavelet::quantise_frame(
+                frame,
+                wavelet,
+                args.wavelet_levels,
+                16. * args.quantisation_factor,
+                16. * chroma_factor,
+            )),
+            (None, None, _) => Ok(quantise_frame(
+                frame,
+                &quant_matrices,
+                args.block_size,
+                args.padding,
+            )),
+        };
+        for frame in frames {
+            let new_frame = if interlaced {
+                interlace::try_map_fields(&frame, quantise)
+            } else {
+                quantise(frame)
+            }
+            .with_context(|| format!("Failed to quantise frame {frame_count}"))?;
             writer
                 .write_frame(new_frame)
                 .context("Failed to write frame")?;
@@ -85,7 +892,332 @@ fn main() -> Result<(), anyhow::Error> {
         }
     }
 
+    if let Some(error) = read_error.take() {
+        return Err(error).with_context(|| format!("Failed to read {}", input_file.display()));
+    }
+    if let Some(error) = spatial_error.take() {
+        return Err(error).context(format!("Failed to process frame {frame_count}"));
+    }
     println!("Wrote {} frames", frame_count);
 
     Ok(())
 }
+
+// Fresh directory for a test's files
+#[cfg(test)]
+fn test_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("squish_{name}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+#[cfg(test)]
+fn count_frames(path: &Path) -> usize {
+    open_input(path, Limits::default())
+        .unwrap()
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap()
+        .len()
+}
+
+#[test]
+fn trims_ranges_of_frames() {
+    let directory = test_directory("trim");
+    let (input, output) = (directory.join("input.y4m"), directory.join("output.y4m"));
+    let header = Header {
+        width: 16,
+        height: 8,
+        interlace_mode: InterlaceMode::Ip,
+        ..Header::default()
+    };
+    generate(&input, Pattern::Gradient, &header, 10).unwrap();
+
+    // Each range is clipped to the frames that exist
+    for (start, count, expected) in [
+        (0, None, 10),
+        (3, Some(4), 4),
+        (8, Some(5), 2),
+        (10, None, 0),
+        (20, Some(1), 0),
+    ] {
+        trim(&input, &output, start, count, Limits::default()).unwrap();
+        assert_eq!(count_frames(&output), expected, "{start} {count:?}");
+    }
+
+    // The trimmed frames are the ones from the start of the range
+    trim(&input, &output, 3, Some(1), Limits::default()).unwrap();
+    let frame = open_input(&output, Limits::default())
+        .unwrap()
+        .into_iter()
+        .next()
+        .unwrap()
+        .unwrap();
+    let expected = generate::generate_frame(Pattern::Gradient, &header, 3);
+    assert_eq!(frame.to_vec(), expected.to_vec());
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn splits_into_numbered_pieces() {
+    let directory = test_directory("split");
+    let input = directory.join("input.y4m");
+    let header = Header {
+        width: 16,
+        height: 8,
+        interlace_mode: InterlaceMode::Ip,
+        ..Header::default()
+    };
+    generate(&input, Pattern::Bars, &header, 7).unwrap();
+
+    split(&input, &directory.join("piece.y4m"), 3, Limits::default()).unwrap();
+    let counts = ["piece_000.y4m", "piece_001.y4m", "piece_002.y4m"]
+        .map(|name| count_frames(&directory.join(name)));
+    assert_eq!(counts, [3, 3, 1]);
+    assert!(!directory.join("piece_003.y4m").exists());
+    assert!(split(&input, &directory.join("piece.y4m"), 0, Limits::default()).is_err());
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn concatenates_compatible_files() {
+    let directory = test_directory("concat");
+    let paths = ["a.y4m", "b.y4m", "c.y4m", "d.y4m"].map(|name| directory.join(name));
+    let output = directory.join("output.y4m");
+    let header = Header {
+        width: 16,
+        height: 8,
+        interlace_mode: InterlaceMode::Ip,
+        ..Header::default()
+    };
+    generate(&paths[0], Pattern::Bars, &header, 2).unwrap();
+    generate(&paths[1], Pattern::Text, &header, 3).unwrap();
+    let wider = Header {
+        width: 32,
+        ..header
+    };
+    generate(&paths[2], Pattern::Bars, &wider, 1).unwrap();
+    let chroma = Header {
+        color_space: yuv4mpeg2::ColorSpace::C444,
+        ..header
+    };
+    generate(&paths[3], Pattern::Bars, &chroma, 1).unwrap();
+
+    concat(&paths[..2], &output, Limits::default()).unwrap();
+    assert_eq!(count_frames(&output), 5);
+
+    // Nothing is written when any input has a different size or color space
+    fs::remove_file(&output).unwrap();
+    for incompatible in [&paths[2], &paths[3]] {
+        let inputs = [paths[0].clone(), incompatible.clone()];
+        assert!(concat(&inputs, &output, Limits::default()).is_err());
+        assert!(!output.exists());
+    }
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn applies_limits_from_the_command_line() {
+    let cli = Cli::parse_from(["squish", "hash", "input.y4m", "--max-width", "8"]);
+    assert_eq!(cli.limits.limits().max_width, 8);
+    assert_eq!(
+        cli.limits.limits().max_frame_bytes,
+        Limits::default().max_frame_bytes
+    );
+
+    let directory = test_directory("limits");
+    let input = directory.join("input.y4m");
+    let header = Header {
+        width: 16,
+        height: 8,
+        interlace_mode: InterlaceMode::Ip,
+        ..Header::default()
+    };
+    generate(&input, Pattern::Bars, &header, 1).unwrap();
+    for mmap in [false, true] {
+        assert!(hash(&input, mmap, cli.limits.limits(), &mut io::sink()).is_err());
+        assert!(hash(&input, mmap, Limits::default(), &mut io::sink()).is_ok());
+    }
+    let short_headers = Limits {
+        max_header_length: 16,
+        ..Limits::default()
+    };
+    assert!(open_input(&input, short_headers).is_err());
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn rejects_interlaced_partitioning() {
+    let directory = test_directory("partition");
+    let input = directory.join("input.y4m");
+    let output = directory.join("output.y4m");
+    let header = Header {
+        width: 64,
+        height: 64,
+        interlace_mode: InterlaceMode::It,
+        ..Header::default()
+    };
+    generate(&input, Pattern::Bars, &header, 1).unwrap();
+    let cli = Cli::parse_from([
+        "squish".as_ref(),
+        "-i".as_ref(),
+        input.as_os_str(),
+        "-o".as_ref(),
+        output.as_os_str(),
+        "--region-size".as_ref(),
+        "32".as_ref(),
+    ]);
+    let error = compress(cli.args, Limits::default()).unwrap_err();
+    assert!(error.to_string().contains("interlaced"));
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn generates_odd_sized_files() {
+    let directory = test_directory("generate");
+    let output = directory.join("output.y4m");
+    for color_space in [
+        ColorSpace::C420jpeg,
+        ColorSpace::C422,
+        ColorSpace::C444,
+        ColorSpace::Cmono,
+    ] {
+        let header = Header {
+            width: 15,
+            height: 9,
+            interlace_mode: InterlaceMode::Ip,
+            color_space,
+            ..Header::default()
+        };
+        generate(&output, Pattern::ZonePlate, &header, 3).unwrap();
+        let frames = open_input(&output, Limits::default())
+            .unwrap()
+            .into_iter()
+            .map(|frame| frame.unwrap().to_vec())
+            .collect::<Vec<_>>();
+        let expected = (0..3)
+            .map(|index| generate::generate_frame(Pattern::ZonePlate, &header, index).to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(frames, expected, "{color_space}");
+    }
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn reports_truncated_input() {
+    let directory = test_directory("truncated");
+    let (input, output) = (directory.join("input.y4m"), directory.join("output.y4m"));
+    let header = Header {
+        width: 16,
+        height: 8,
+        interlace_mode: InterlaceMode::Ip,
+        ..Header::default()
+    };
+    let complete = directory.join("complete.y4m");
+    generate(&complete, Pattern::Bars, &header, 3).unwrap();
+    let bytes = fs::read(&complete).unwrap();
+    fs::write(&input, &bytes[..bytes.len() - 1]).unwrap();
+    let truncated = |result: Result<(), anyhow::Error>| {
+        let error = result.unwrap_err();
+        assert!(format!("{error:#}").contains("partway through a frame"), "{error:#}");
+    };
+
+    truncated(trim(&input, &output, 1, None, Limits::default()));
+    truncated(split(&input, &output, 2, Limits::default()));
+    truncated(concat(&[complete, input.clone()], &output, Limits::default()));
+    let cli = Cli::parse_from([
+        "squish".as_ref(),
+        "-i".as_ref(),
+        input.as_os_str(),
+        "-o".as_ref(),
+        output.as_os_str(),
+    ]);
+    truncated(compress(cli.args, Limits::default()));
+    // The frames before the truncated one are still written
+    assert_eq!(count_frames(&output), 2);
+
+    // Ranges that end before the truncated frame don't read it
+    trim(&input, &output, 0, Some(2), Limits::default()).unwrap();
+    assert_eq!(count_frames(&output), 2);
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn hashes_and_compares_files() {
+    let directory = test_directory("compare");
+    let paths = ["a.y4m", "b.y4m", "c.y4m", "d.y4m", "e.y4m"].map(|name| directory.join(name));
+    let header = Header {
+        width: 16,
+        height: 8,
+        interlace_mode: InterlaceMode::Ip,
+        ..Header::default()
+    };
+    generate(&paths[0], Pattern::Gradient, &header, 3).unwrap();
+    fs::copy(&paths[0], &paths[1]).unwrap();
+    generate(&paths[2], Pattern::Text, &header, 3).unwrap();
+    generate(&paths[3], Pattern::Gradient, &header, 2).unwrap();
+    let bytes = fs::read(&paths[0]).unwrap();
+    fs::write(&paths[4], &bytes[..bytes.len() - 1]).unwrap();
+    let [same, copy, other, shorter, truncated] = &paths;
+
+    let hashes = |path: &Path, mmap| {
+        let mut output = vec![];
+        hash(path, mmap, Limits::default(), &mut output)?;
+        anyhow::Ok(String::from_utf8(output).unwrap())
+    };
+    for mmap in [false, true] {
+        let digests = hashes(same, mmap).unwrap();
+        assert_eq!(digests.lines().count(), 3);
+        assert_eq!(hashes(copy, mmap).unwrap(), digests);
+        assert_ne!(hashes(other, mmap).unwrap(), digests);
+        assert!(hashes(truncated, mmap).is_err());
+    }
+
+    let compare = |first: &Path, second: &Path| diff(first, second, Limits::default());
+    let difference = |first: &Path, second: &Path| match compare(first, second).unwrap() {
+        Comparison::Different(description) => description,
+        Comparison::Identical(_) => panic!("Files compared equal"),
+    };
+    assert!(matches!(compare(same, copy), Ok(Comparison::Identical(3))));
+    assert!(difference(same, other).starts_with("Frame 0"));
+    assert!(difference(same, shorter).contains("ends after 2 frames"));
+    // Files cut off at the same point aren't identical, as neither can be read
+    assert!(compare(truncated, truncated).is_err());
+    assert!(compare(same, truncated).is_err());
+    fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn skips_deinterlacing_progressive_input() {
+    let directory = test_directory("deinterlace");
+    let (input, output) = (directory.join("input.y4m"), directory.join("output.y4m"));
+    let deinterlace = |interlace_mode| {
+        let header = Header {
+            width: 16,
+            height: 8,
+            frame_rate_numerator: 25,
+            frame_rate_denominator: 1,
+            interlace_mode,
+            ..Header::default()
+        };
+        generate(&input, Pattern::ZonePlate, &header, 3).unwrap();
+        let cli = Cli::parse_from([
+            "squish".as_ref(),
+            "-i".as_ref(),
+            input.as_os_str(),
+            "-o".as_ref(),
+            output.as_os_str(),
+            "--deinterlace".as_ref(),
+            "bob".as_ref(),
+        ]);
+        compress(cli.args, Limits::default()).unwrap();
+        let reader = open_input(&output, Limits::default()).unwrap();
+        (reader.header.frame_rate_numerator, count_frames(&output))
+    };
+
+    // Bobbing doubles the frame rate of interlaced input, and leaves progressive input alone
+    assert_eq!(deinterlace(InterlaceMode::Ip), (25, 3));
+    assert_eq!(deinterlace(InterlaceMode::It), (50, 6));
+    fs::remove_dir_all(directory).unwrap();
+}
@@ -0,0 +1,83 @@
+use crate::yuv4mpeg2::Frame;
+
+// Peak signal-to-noise ratio in decibels between two equally sized sets of 8-bit samples.
+// Identical inputs give infinity
+pub fn psnr(original: &[u8], reconstructed: &[u8]) -> f64 {
+    let squared_error: f64 = original
+        .iter()
+        .zip(reconstructed)
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    let mean_squared_error = squared_error / original.len().max(1) as f64;
+    10. * (255. * 255. / mean_squared_error).log10()
+}
+
+pub const PLANE_NAMES: [&str; 3] = ["Y", "Cb", "Cr"];
+
+// MD5 digest of each of the frame's planes
+pub fn plane_md5s(frame: &Frame) -> [md5::Digest; 3] {
+    frame.planes().map(|(values, _, _)| md5::compute(values))
+}
+
+// Location and values of a sample that differs between two frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Difference {
+    pub plane: usize,
+    pub x: usize,
+    pub y: usize,
+    pub first: u8,
+    pub second: u8,
+}
+
+// The first sample, in plane order and then raster order, that differs between two frames of
+// the same size and color space
+pub fn first_difference(first: &Frame, second: &Frame) -> Option<Difference> {
+    first
+        .planes()
+        .iter()
+        .zip(second.planes())
+        .enumerate()
+        .find_map(|(plane, (&(first, width, _), (second, _, _)))| {
+            let index = first.iter().zip(second).position(|(a, b)| a != b)?;
+            Some(Difference {
+                plane,
+                x: index % width,
+                y: index / width,
+                first: first[index],
+                second: second[index],
+            })
+        })
+}
+
+#[test]
+fn finds_first_difference() {
+    use crate::yuv4mpeg2::ColorSpace;
+
+    let frame = Frame {
+        width: 4,
+        height: 2,
+        color_space: ColorSpace::C420jpeg,
+        data_y: (0..8).collect(),
+        data_cb: vec![128, 128],
+        data_cr: vec![128, 128],
+    };
+    let mut changed = frame.clone();
+    assert_eq!(first_difference(&frame, &changed), None);
+    assert_eq!(plane_md5s(&frame), plane_md5s(&changed));
+
+    changed.data_cr[1] = 130;
+    changed.data_cb[1] = 129;
+    assert_eq!(
+        first_difference(&frame, &changed),
+        Some(Difference {
+            plane: 1,
+            x: 1,
+            y: 0,
+            first: 128,
+            second: 129,
+        })
+    );
+    let (original, modified) = (plane_md5s(&frame), plane_md5s(&changed));
+    assert_eq!(original[0], modified[0]);
+    assert_ne!(original[1], modified[1]);
+}
@@ -0,0 +1,81 @@
+// Strategies for filling the part of a block that lies past the right or bottom edge of the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Padding {
+    /// Fill with zero
+    Zero,
+    /// Repeat the last row and column of the image
+    Replicate,
+    /// Reflect the image about its edge
+    Mirror,
+    /// Fill with the mean of the block's pixels that lie inside the image
+    Mean,
+}
+
+// Copies the size x size block with its top-left corner at (start_x, start_y) into `block`
+// in row-major order, padding any part that lies past the edge of the image
+pub fn read_block(
+    values: &[u8],
+    width: usize,
+    height: usize,
+    (start_x, start_y): (usize, usize),
+    size: usize,
+    padding: Padding,
+    block: &mut [u8],
+) {
+    let end_x = usize::min(start_x + size, width);
+    let end_y = usize::min(start_y + size, height);
+
+    let fill = match padding {
+        Padding::Mean => {
+            let (sum, count) = (start_y..end_y)
+                .flat_map(|row| &values[row * width + start_x..row * width + end_x])
+                .fold((0, 0), |(sum, count), &value| (sum + value as usize, count + 1));
+            (sum / count.max(1)) as u8
+        }
+        _ => 0,
+    };
+
+    for j in 0..size {
+        for i in 0..size {
+            let (x, y) = (start_x + i, start_y + j);
+            block[j * size + i] = if x < width && y < height {
+                values[y * width + x]
+            } else {
+                match padding {
+                    Padding::Zero | Padding::Mean => fill,
+                    Padding::Replicate => values[y.min(height - 1) * width + x.min(width - 1)],
+                    Padding::Mirror => values[mirror(y, height) * width + mirror(x, width)],
+                }
+            };
+        }
+    }
+}
+
+// Reflects a coordinate past the end of the range back inside it, so that the sample
+// just past the edge repeats the last sample, as in the symmetric extension assumed by the DCT
+pub(crate) fn mirror(position: usize, length: usize) -> usize {
+    if position < length {
+        position
+    } else {
+        (2 * length).saturating_sub(position + 1).min(length - 1)
+    }
+}
+
+#[test]
+fn pads_partial_blocks() {
+    // A 3x2 image in the top-left of a 4x4 block
+    let values = [10, 20, 30, 40, 50, 60];
+    let mut block = [0; 16];
+
+    read_block(&values, 3, 2, (0, 0), 4, Padding::Zero, &mut block);
+    assert_eq!(block, [10, 20, 30, 0, 40, 50, 60, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    read_block(&values, 3, 2, (0, 0), 4, Padding::Replicate, &mut block);
+    assert_eq!(block, [10, 20, 30, 30, 40, 50, 60, 60, 40, 50, 60, 60, 40, 50, 60, 60]);
+
+    read_block(&values, 3, 2, (0, 0), 4, Padding::Mirror, &mut block);
+    assert_eq!(block, [10, 20, 30, 30, 40, 50, 60, 60, 40, 50, 60, 60, 10, 20, 30, 30]);
+
+    read_block(&values, 3, 2, (0, 0), 4, Padding::Mean, &mut block);
+    assert_eq!(block, [10, 20, 30, 35, 40, 50, 60, 35, 35, 35, 35, 35, 35, 35, 35, 35]);
+}
@@ -0,0 +1,463 @@
+use crate::dct_2d::{self, MacroBlock, QuantMatrices, BLOCK_SIZES};
+use crate::padding::{self, Padding};
+use crate::yuv4mpeg2::Frame;
+
+// Sizes of the square regions that are each divided into a quadtree of transform blocks
+pub const REGION_SIZES: [usize; 2] = [32, 64];
+
+const MIN_BLOCK_SIZE: usize = BLOCK_SIZES[0];
+const MAX_BLOCK_SIZE: usize = BLOCK_SIZES[BLOCK_SIZES.len() - 1];
+
+// Flags are only read back to check that they round trip
+#[cfg(test)]
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unable to parse partition flags: {0}")]
+    ParseFlags(String),
+}
+
+// How a square region is divided into transform blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Partition {
+    Block,
+    // Quarters in the order top-left, top-right, bottom-left, bottom-right
+    Split(Box<[Partition; 4]>),
+}
+
+impl Partition {
+    // Appends a split flag for each node in depth-first order, as it would be coded in a
+    // bitstream. Nodes with only one choice have no flag: regions larger than the largest block
+    // always split, and the smallest blocks never do
+    pub fn write_flags(&self, size: usize, flags: &mut String) {
+        if size > MIN_BLOCK_SIZE && size <= MAX_BLOCK_SIZE {
+            flags.push(match self {
+                Partition::Block => '0',
+                Partition::Split(_) => '1',
+            });
+        }
+        if let Partition::Split(quarters) = self {
+            for quarter in quarters.iter() {
+                quarter.write_flags(size / 2, flags);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn read_flags(flags: &mut impl Iterator<Item = char>, size: usize) -> Result<Self, Error> {
+        let split = if size > MAX_BLOCK_SIZE {
+            true
+        } else if size > MIN_BLOCK_SIZE {
+            match flags.next() {
+                Some('0') => false,
+                Some('1') => true,
+                Some(other) => return Err(Error::ParseFlags(format!("unexpected {other:?}"))),
+                None => return Err(Error::ParseFlags("too few flags".to_string())),
+            }
+        } else {
+            false
+        };
+        if !split {
+            return Ok(Partition::Block);
+        }
+        let mut quarter = || Partition::read_flags(flags, size / 2);
+        Ok(Partition::Split(Box::new([
+            quarter()?,
+            quarter()?,
+            quarter()?,
+            quarter()?,
+        ])))
+    }
+
+    // Position and size of each transform block, relative to the corner of the region
+    pub fn blocks(&self, size: usize) -> Vec<(usize, usize, usize)> {
+        match self {
+            Partition::Block => vec![(0, 0, size)],
+            Partition::Split(quarters) => {
+                let half = size / 2;
+                let corners = [(0, 0), (half, 0), (0, half), (half, half)];
+                quarters
+                    .iter()
+                    .zip(corners)
+                    .flat_map(|(quarter, (x, y))| {
+                        quarter
+                            .blocks(half)
+                            .into_iter()
+                            .map(move |(i, j, block_size)| (x + i, y + j, block_size))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+// Split flags of every region of a plane, in raster order
+pub fn flags(partitions: &[Partition], region_size: usize) -> String {
+    let mut flags = String::new();
+    for partition in partitions {
+        partition.write_flags(region_size, &mut flags);
+    }
+    flags
+}
+
+#[cfg(test)]
+pub fn parse_flags(
+    flags: &str,
+    region_size: usize,
+    region_count: usize,
+) -> Result<Vec<Partition>, Error> {
+    let mut flags = flags.chars();
+    let partitions = (0..region_count)
+        .map(|_| Partition::read_flags(&mut flags, region_size))
+        .collect::<Result<Vec<_>, _>>()?;
+    match flags.next() {
+        Some(_) => Err(Error::ParseFlags("too many flags".to_string())),
+        None => Ok(partitions),
+    }
+}
+
+// Lagrange multiplier that weighs bits against squared error. At high rates a uniform quantiser
+// with step size Δ trades one for the other at (ln 2 / 6)·Δ², so this uses the mean luma step
+pub fn default_lambda(quant_matrices: &QuantMatrices) -> f64 {
+    let mean_step = quant_matrices.luma.as_flattened().iter().sum::<f64>() / 64.;
+    std::f64::consts::LN_2 / 6. * mean_step * mean_step
+}
+
+// Quantises each plane in regions of the given size, dividing each region into the transform
+// blocks that minimise distortion + λ·bits. Returns the partitions of each plane's regions in
+// raster order
+pub fn quantise_frame(
+    frame: Frame,
+    quant_matrices: &QuantMatrices,
+    region_size: usize,
+    lambda: f64,
+    padding: Padding,
+) -> (Frame, [Vec<Partition>; 3]) {
+    let luma = PlaneCoder::new(&quant_matrices.luma, lambda);
+    let chroma = PlaneCoder::new(&quant_matrices.chroma, lambda);
+    let mut partitions: [Vec<Partition>; 3] = Default::default();
+    let quantised = frame.map_planes(|index, values, width, height| {
+        let coder = match index {
+            0 => &luma,
+            _ => &chroma,
+        };
+        let (values, plane_partitions) =
+            coder.quantise_plane(values, width, height, region_size, padding);
+        partitions[index] = plane_partitions;
+        values
+    });
+    (quantised, partitions)
+}
+
+// Marks the top and left edges of every transform block on the luma plane, in black on bright
+// pixels and white on dark ones
+pub fn draw_partitions(frame: &Frame, partitions: &[Partition], region_size: usize) -> Frame {
+    let mut drawn = frame.clone();
+    let (width, height) = (frame.width, frame.height);
+    let regions_x = width.div_ceil(region_size);
+    for (index, partition) in partitions.iter().enumerate() {
+        let (region_x, region_y) = (
+            index % regions_x * region_size,
+            index / regions_x * region_size,
+        );
+        for (x, y, size) in partition.blocks(region_size) {
+            let (start_x, start_y) = (region_x + x, region_y + y);
+            let edge = (0..size)
+                .map(|i| (start_x + i, start_y))
+                .chain((1..size).map(|j| (start_x, start_y + j)));
+            for (x, y) in edge.filter(|&(x, y)| x < width && y < height) {
+                let value = &mut drawn.data_y[y * width + x];
+                *value = if *value > 128 { 0 } else { 255 };
+            }
+        }
+    }
+    drawn
+}
+
+// Chooses and codes the partitions of one plane
+struct PlaneCoder {
+    // Step sizes and coefficient scan order for each of BLOCK_SIZES
+    quant_matrices: Vec<Vec<f64>>,
+    scans: Vec<Vec<usize>>,
+    lambda: f64,
+}
+
+// A region read from a plane, padded to its full size
+struct Region<'a> {
+    values: &'a [u8],
+    size: usize,
+    // Width and height of the part that lies inside the picture
+    visible: (usize, usize),
+}
+
+impl PlaneCoder {
+    fn new(quant_matrix: &dct_2d::QuantMatrix, lambda: f64) -> Self {
+        PlaneCoder {
+            quant_matrices: BLOCK_SIZES
+                .iter()
+                .map(|&size| dct_2d::resample_matrix(quant_matrix, size))
+                .collect(),
+            scans: BLOCK_SIZES
+                .iter()
+                .map(|&size| diagonal_scan(size))
+                .collect(),
+            lambda,
+        }
+    }
+
+    fn quantise_plane(
+        &self,
+        values: &[u8],
+        width: usize,
+        height: usize,
+        region_size: usize,
+        padding: Padding,
+    ) -> (Vec<u8>, Vec<Partition>) {
+        let mut output = vec![0; width * height];
+        let mut partitions = Vec::new();
+        let mut region_values = vec![0; region_size * region_size];
+        let mut reconstructed = vec![0; region_size * region_size];
+        for start_y in (0..height).step_by(region_size) {
+            for start_x in (0..width).step_by(region_size) {
+                let start = (start_x, start_y);
+                padding::read_block(
+                    values,
+                    width,
+                    height,
+                    start,
+                    region_size,
+                    padding,
+                    &mut region_values,
+                );
+                let visible = (
+                    usize::min(width - start_x, region_size),
+                    usize::min(height - start_y, region_size),
+                );
+                let region = Region {
+                    values: &region_values,
+                    size: region_size,
+                    visible,
+                };
+                let (partition, _) = self.choose(&region, (0, 0), region_size, &mut reconstructed);
+                partitions.push(partition);
+
+                for row in 0..visible.1 {
+                    let output_start = (start_y + row) * width + start_x;
+                    output[output_start..output_start + visible.0].copy_from_slice(
+                        &reconstructed[row * region_size..row * region_size + visible.0],
+                    );
+                }
+            }
+        }
+        (output, partitions)
+    }
+
+    // Codes the block at (x, y) in the region either whole or as four quarters, whichever costs
+    // less, and writes its reconstruction into `reconstructed`. Returns the choice and its cost
+    fn choose(
+        &self,
+        region: &Region,
+        (x, y): (usize, usize),
+        size: usize,
+        reconstructed: &mut [u8],
+    ) -> (Partition, f64) {
+        // A block that's entirely outside the picture is never seen, so it isn't coded
+        if x >= region.visible.0 || y >= region.visible.1 {
+            return (Partition::Block, 0.);
+        }
+        let flag_cost = match size > MIN_BLOCK_SIZE && size <= MAX_BLOCK_SIZE {
+            true => self.lambda,
+            false => 0.,
+        };
+
+        let whole = (size <= MAX_BLOCK_SIZE).then(|| self.code_block(region, (x, y), size));
+        if size == MIN_BLOCK_SIZE {
+            let (pixels, cost) = whole.expect("the smallest block size can be coded");
+            write_block(reconstructed, region.size, (x, y), size, &pixels);
+            return (Partition::Block, cost);
+        }
+
+        let half = size / 2;
+        let mut split_cost = flag_cost;
+        let quarters = [(x, y), (x + half, y), (x, y + half), (x + half, y + half)].map(|corner| {
+            let (partition, cost) = self.choose(region, corner, half, reconstructed);
+            split_cost += cost;
+            partition
+        });
+        match whole {
+            Some((pixels, cost)) if cost + flag_cost <= split_cost => {
+                write_block(reconstructed, region.size, (x, y), size, &pixels);
+                (Partition::Block, cost + flag_cost)
+            }
+            _ => (Partition::Split(Box::new(quarters)), split_cost),
+        }
+    }
+
+    // Quantises a single block, returning its reconstruction and rate-distortion cost
+    fn code_block(
+        &self,
+        region: &Region,
+        (x, y): (usize, usize),
+        size: usize,
+    ) -> (MacroBlock, f64) {
+        let level = BLOCK_SIZES
+            .iter()
+            .position(|&block_size| block_size == size)
+            .expect("blocks are one of BLOCK_SIZES");
+        let quant_matrix = &self.quant_matrices[level];
+
+        let block = (0..size)
+            .flat_map(|row| &region.values[(y + row) * region.size + x..][..size])
+            .copied()
+            .collect::<MacroBlock>();
+        let quantised = dct_2d::quantise(dct_2d::transform(&block, size), quant_matrix);
+        let bits = estimate_bits(&quantised, &self.scans[level]);
+        let pixels = dct_2d::inverse_transform(dct_2d::dequantise(quantised, quant_matrix), size);
+
+        // Only the pixels inside the picture count towards the distortion
+        let (visible_width, visible_height) = (
+            usize::min(region.visible.0 - x, size),
+            usize::min(region.visible.1 - y, size),
+        );
+        let distortion: f64 = (0..visible_height)
+            .flat_map(|row| (0..visible_width).map(move |column| row * size + column))
+            .map(|index| (block[index] as f64 - pixels[index] as f64).powi(2))
+            .sum();
+        (pixels, distortion + self.lambda * bits)
+    }
+}
+
+fn write_block(
+    values: &mut [u8],
+    stride: usize,
+    (x, y): (usize, usize),
+    size: usize,
+    block: &[u8],
+) {
+    for row in 0..size {
+        values[(y + row) * stride + x..][..size].copy_from_slice(&block[row * size..][..size]);
+    }
+}
+
+// Coefficient indices ordered by anti-diagonal, from the lowest frequencies to the highest
+fn diagonal_scan(size: usize) -> Vec<usize> {
+    let mut scan = (0..size * size).collect::<Vec<_>>();
+    scan.sort_by_key(|&index| (index / size + index % size, index / size));
+    scan
+}
+
+// Estimates the bits to code a block of quantised coefficients as (run of zeros, level) pairs in
+// scan order with exponential-Golomb codes, followed by an end-of-block marker
+fn estimate_bits(levels: &[f64], scan: &[usize]) -> f64 {
+    let mut bits = 1;
+    let mut run = 0;
+    for &index in scan {
+        let magnitude = levels[index].abs() as usize;
+        if magnitude == 0 {
+            run += 1;
+        } else {
+            // The magnitude is at least one, and is followed by a sign bit
+            bits += exp_golomb_length(run) + exp_golomb_length(magnitude - 1) + 1;
+            run = 0;
+        }
+    }
+    bits as f64
+}
+
+fn exp_golomb_length(value: usize) -> usize {
+    2 * (value + 1).ilog2() as usize + 1
+}
+
+#[test]
+fn round_trips_partition_flags() {
+    let partition = Partition::Split(Box::new([
+        Partition::Block,
+        Partition::Split(Box::new([
+            Partition::Block,
+            Partition::Block,
+            Partition::Block,
+            Partition::Block,
+        ])),
+        Partition::Block,
+        Partition::Block,
+    ]));
+    // A 64 region always splits, so its first flag is for the top-left 32x32 quarter
+    let partitions = [partition.clone(), Partition::Block];
+    assert_eq!(flags(&partitions, 32), "1010000000");
+    assert_eq!(parse_flags("1010000000", 32, 2).unwrap(), partitions);
+    assert_eq!(flags(&[partition], 64), "01000000");
+
+    for bad in ["101000000", "10100000000", "1010x00000"] {
+        assert!(parse_flags(bad, 32, 2).is_err());
+    }
+
+    let blocks = parse_flags("1010000000", 32, 2).unwrap()[0].blocks(32);
+    assert_eq!(
+        blocks,
+        [
+            (0, 0, 16),
+            (16, 0, 8),
+            (24, 0, 8),
+            (16, 8, 8),
+            (24, 8, 8),
+            (0, 16, 16),
+            (16, 16, 16)
+        ]
+    );
+}
+
+#[test]
+fn splits_detailed_regions() {
+    use crate::{
+        generate::{generate_frame, Pattern},
+        metrics::psnr,
+        yuv4mpeg2::{ColorSpace, Header},
+    };
+
+    // Detail in the left half of the picture and a flat right half
+    let header = Header {
+        width: 128,
+        height: 64,
+        color_space: ColorSpace::C444,
+        ..Header::default()
+    };
+    let mut frame = generate_frame(Pattern::ZonePlate, &header, 0);
+    for row in frame.data_y.chunks_mut(128) {
+        row[64..].fill(100);
+    }
+
+    let quant_matrices = QuantMatrices::default();
+    let lambda = default_lambda(&quant_matrices);
+    let block_count = |partitions: &[Partition]| {
+        partitions
+            .iter()
+            .map(|partition| partition.blocks(64).len())
+            .collect::<Vec<_>>()
+    };
+    let (quantised, partitions) = quantise_frame(
+        frame.clone(),
+        &quant_matrices,
+        64,
+        lambda,
+        Padding::Replicate,
+    );
+    let counts = block_count(&partitions[0]);
+    dbg!(&counts);
+    assert_eq!(counts.len(), 2);
+    assert!(counts[0] > 4);
+    assert_eq!(counts[1], 4);
+
+    // Bits cost more at a higher λ, so there are fewer and larger blocks
+    let (coarse, coarse_partitions) = quantise_frame(
+        frame.clone(),
+        &quant_matrices,
+        64,
+        lambda * 100.,
+        Padding::Replicate,
+    );
+    assert!(block_count(&coarse_partitions[0])[0] < counts[0]);
+    assert!(psnr(&frame.data_y, &quantised.data_y) > psnr(&frame.data_y, &coarse.data_y));
+
+    let drawn = draw_partitions(&quantised, &partitions[0], 64);
+    assert_ne!(drawn.data_y, quantised.data_y);
+    assert_eq!(drawn.data_cb, quantised.data_cb);
+}
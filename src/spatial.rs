@@ -0,0 +1,421 @@
+use std::{f64::consts::PI, str::FromStr};
+
+use crate::yuv4mpeg2::{ColorSpace, Frame};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unable to parse size, expected WIDTHxHEIGHT")]
+    ParseSize,
+    #[error("Unable to parse rectangle, expected WIDTHxHEIGHT+X+Y")]
+    ParseRect,
+    #[error("Crop rectangle lies outside the frame")]
+    CropOutOfBounds,
+    #[error("Crop rectangle must be aligned to the {0}x{1} chroma subsampling")]
+    CropMisaligned(usize, usize),
+    #[error("Width and height must both be at least 1")]
+    EmptySize,
+    #[error("Frames can only be padded to a multiple of at least 1")]
+    ZeroPadMultiple,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ScaleFilter {
+    Bilinear,
+    Bicubic,
+    Lanczos,
+}
+
+impl FromStr for Size {
+    type Err = Error;
+
+    // Parses a size of the form 1920x1080
+    fn from_str(size_string: &str) -> Result<Self, Error> {
+        let (width, height) = size_string.split_once('x').ok_or(Error::ParseSize)?;
+        let size = Size {
+            width: width.parse().map_err(|_| Error::ParseSize)?,
+            height: height.parse().map_err(|_| Error::ParseSize)?,
+        };
+        if size.width == 0 || size.height == 0 {
+            return Err(Error::EmptySize);
+        }
+        Ok(size)
+    }
+}
+
+impl FromStr for Rect {
+    type Err = Error;
+
+    // Parses a rectangle of the form 1280x720+320+180, giving the size followed by the offset
+    fn from_str(rect_string: &str) -> Result<Self, Error> {
+        let mut parts = rect_string.split('+');
+        let size =
+            Size::from_str(parts.next().ok_or(Error::ParseRect)?).map_err(|_| Error::ParseRect)?;
+        let mut offset = || -> Result<usize, Error> {
+            parts
+                .next()
+                .unwrap_or("0")
+                .parse()
+                .map_err(|_| Error::ParseRect)
+        };
+        let (x, y) = (offset()?, offset()?);
+        Ok(Rect {
+            x,
+            y,
+            width: size.width,
+            height: size.height,
+        })
+    }
+}
+
+// Checks that a crop rectangle lies within frames of the given size, and that its position and
+// size are multiples of the chroma subsampling so the chroma planes cover exactly the same area
+pub fn check_crop(
+    width: usize,
+    height: usize,
+    color_space: ColorSpace,
+    rect: Rect,
+) -> Result<(), Error> {
+    let right = rect.x.checked_add(rect.width).ok_or(Error::CropOutOfBounds)?;
+    let bottom = rect.y.checked_add(rect.height).ok_or(Error::CropOutOfBounds)?;
+    if right > width || bottom > height {
+        return Err(Error::CropOutOfBounds);
+    }
+    let (horizontal, vertical) = color_space.subsampling();
+    if [rect.x, rect.width].iter().any(|v| v % horizontal != 0)
+        || [rect.y, rect.height].iter().any(|v| v % vertical != 0)
+    {
+        return Err(Error::CropMisaligned(horizontal, vertical));
+    }
+    Ok(())
+}
+
+// Cuts out a rectangle of the frame
+pub fn crop(frame: &Frame, rect: Rect) -> Result<Frame, Error> {
+    check_crop(frame.width, frame.height, frame.color_space, rect)?;
+    let (horizontal, vertical) = frame.color_space.subsampling();
+
+    Ok(resize_planes(
+        frame,
+        rect.width,
+        rect.height,
+        |index, values, width, _, new_width, new_height| {
+            let (x, y) = match index {
+                0 => (rect.x, rect.y),
+                _ => (rect.x / horizontal, rect.y / vertical),
+            };
+            (y..y + new_height)
+                .flat_map(|row| &values[row * width + x..row * width + x + new_width])
+                .copied()
+                .collect()
+        },
+    ))
+}
+
+// Size of frames of the given size once padded up to a multiple of another size
+pub fn padded_size(width: usize, height: usize, multiple: usize) -> Result<Size, Error> {
+    if multiple == 0 {
+        return Err(Error::ZeroPadMultiple);
+    }
+    Ok(Size {
+        width: width.div_ceil(multiple) * multiple,
+        height: height.div_ceil(multiple) * multiple,
+    })
+}
+
+// Extends the right and bottom of the frame up to a multiple of the given size by
+// replicating the last column and row
+pub fn pad(frame: &Frame, multiple: usize) -> Result<Frame, Error> {
+    let size = padded_size(frame.width, frame.height, multiple)?;
+
+    Ok(resize_planes(
+        frame,
+        size.width,
+        size.height,
+        |_, values, width, height, new_width, new_height| {
+            (0..new_height)
+                .flat_map(|row| {
+                    let row = row.min(height - 1);
+                    (0..new_width).map(move |x| values[row * width + x.min(width - 1)])
+                })
+                .collect()
+        },
+    ))
+}
+
+// Resamples the frame to a new size using a separable filter. Neither the frame nor the new size
+// can be empty, as there would be nothing to filter
+pub fn scale(frame: &Frame, size: Size, filter: ScaleFilter) -> Result<Frame, Error> {
+    if [frame.width, frame.height, size.width, size.height].contains(&0) {
+        return Err(Error::EmptySize);
+    }
+    Ok(resize_planes(
+        frame,
+        size.width,
+        size.height,
+        |_, values, width, height, new_width, new_height| {
+            let horizontal_taps = filter_taps(width, new_width, filter);
+            let vertical_taps = filter_taps(height, new_height, filter);
+
+            // Filter along rows, then along columns of the intermediate result
+            let mut intermediate = vec![0.; new_width * height];
+            for row in 0..height {
+                for (x, taps) in horizontal_taps.iter().enumerate() {
+                    intermediate[row * new_width + x] = taps
+                        .iter()
+                        .map(|&(index, weight)| values[row * width + index] as f64 * weight)
+                        .sum();
+                }
+            }
+            let mut output = vec![0; new_width * new_height];
+            for (row, taps) in vertical_taps.iter().enumerate() {
+                for x in 0..new_width {
+                    let value: f64 = taps
+                        .iter()
+                        .map(|&(index, weight)| intermediate[index * new_width + x] * weight)
+                        .sum();
+                    output[row * new_width + x] = value.round().clamp(0., 255.) as u8;
+                }
+            }
+            output
+        },
+    ))
+}
+
+// Builds a frame of a new size, where each output plane is computed from the corresponding input
+// plane. The operation receives the plane index, data, width and height, and the new width and height
+fn resize_planes(
+    frame: &Frame,
+    new_width: usize,
+    new_height: usize,
+    mut operation: impl FnMut(usize, &[u8], usize, usize, usize, usize) -> Vec<u8>,
+) -> Frame {
+    let mut resized = Frame {
+        width: new_width,
+        height: new_height,
+        color_space: frame.color_space,
+        data_y: vec![],
+        data_cb: vec![],
+        data_cr: vec![],
+    };
+    let (chroma_width, chroma_height) = (resized.chroma_width(), resized.chroma_height());
+    let [y, cb, cr] = frame.planes();
+    resized.data_y = operation(0, y.0, y.1, y.2, new_width, new_height);
+    resized.data_cb = operation(1, cb.0, cb.1, cb.2, chroma_width, chroma_height);
+    resized.data_cr = operation(2, cr.0, cr.1, cr.2, chroma_width, chroma_height);
+    resized
+}
+
+// For every output sample, the input sample indices and normalised weights that contribute to it.
+// The kernel is widened when downscaling so it also acts as an anti-aliasing filter
+fn filter_taps(length: usize, new_length: usize, filter: ScaleFilter) -> Vec<Vec<(usize, f64)>> {
+    let ratio = length as f64 / new_length as f64;
+    let stretch = ratio.max(1.);
+    let support = match filter {
+        ScaleFilter::Bilinear => 1.,
+        ScaleFilter::Bicubic => 2.,
+        ScaleFilter::Lanczos => 3.,
+    } * stretch;
+
+    (0..new_length)
+        .map(|i| {
+            let centre = (i as f64 + 0.5) * ratio - 0.5;
+            let first = (centre - support).floor() as isize;
+            let last = (centre + support).ceil() as isize;
+            let mut taps = (first..=last)
+                .map(|j| {
+                    let weight = kernel((j as f64 - centre) / stretch, filter);
+                    (j.clamp(0, length as isize - 1) as usize, weight)
+                })
+                .filter(|&(_, weight)| weight != 0.)
+                .collect::<Vec<_>>();
+            let total: f64 = taps.iter().map(|&(_, weight)| weight).sum();
+            for (_, weight) in taps.iter_mut() {
+                *weight /= total;
+            }
+            taps
+        })
+        .collect()
+}
+
+fn kernel(x: f64, filter: ScaleFilter) -> f64 {
+    let x = x.abs();
+    match filter {
+        ScaleFilter::Bilinear => (1. - x).max(0.),
+        // Keys cubic convolution with a = -0.5 (Catmull-Rom)
+        ScaleFilter::Bicubic => {
+            if x < 1. {
+                1.5 * x.powi(3) - 2.5 * x.powi(2) + 1.
+            } else if x < 2. {
+                -0.5 * x.powi(3) + 2.5 * x.powi(2) - 4. * x + 2.
+            } else {
+                0.
+            }
+        }
+        ScaleFilter::Lanczos => {
+            if x == 0. {
+                1.
+            } else if x < 3. {
+                3. * (PI * x).sin() * (PI * x / 3.).sin() / (PI * x).powi(2)
+            } else {
+                0.
+            }
+        }
+    }
+}
+
+#[test]
+fn crops_and_pads_frames() {
+    use crate::yuv4mpeg2::ColorSpace;
+
+    let frame = Frame {
+        width: 6,
+        height: 4,
+        color_space: ColorSpace::C420,
+        data_y: (0..24).collect(),
+        data_cb: (0..6).collect(),
+        data_cr: (0..6).collect(),
+    };
+
+    let rect = Rect::from_str("2x2+2+2").unwrap();
+    let cropped = crop(&frame, rect).unwrap();
+    assert_eq!(cropped.data_y, vec![14, 15, 20, 21]);
+    assert_eq!(cropped.data_cb, vec![4]);
+    assert!(matches!(
+        crop(&frame, Rect::from_str("2x2+1+0").unwrap()),
+        Err(Error::CropMisaligned(2, 2))
+    ));
+    assert!(matches!(
+        crop(&frame, Rect::from_str("4x4+4+0").unwrap()),
+        Err(Error::CropOutOfBounds)
+    ));
+    assert!(matches!(
+        crop(&frame, Rect::from_str("2x2+18446744073709551614+0").unwrap()),
+        Err(Error::CropOutOfBounds)
+    ));
+
+    let padded = pad(&frame, 8).unwrap();
+    assert_eq!((padded.width, padded.height), (8, 8));
+    assert_eq!(padded.data_y[7], 5); // last column replicated
+    assert_eq!(padded.data_y[7 * 8], 18); // last row replicated
+    assert_eq!(padded.data_cb.len(), 16);
+    assert!(matches!(pad(&frame, 0), Err(Error::ZeroPadMultiple)));
+}
+
+#[test]
+fn scales_frames() {
+    use crate::yuv4mpeg2::ColorSpace;
+
+    let frame = Frame {
+        width: 8,
+        height: 8,
+        color_space: ColorSpace::C444,
+        data_y: (0..64).map(|i| (i * 3) as u8).collect(),
+        data_cb: vec![77; 64],
+        data_cr: vec![200; 64],
+    };
+
+    for filter in [
+        ScaleFilter::Bilinear,
+        ScaleFilter::Bicubic,
+        ScaleFilter::Lanczos,
+    ] {
+        // Scaling to the same size leaves the frame unchanged
+        let same = scale(
+            &frame,
+            Size {
+                width: 8,
+                height: 8,
+            },
+            filter,
+        )
+        .unwrap();
+        assert_eq!(same.data_y, frame.data_y);
+
+        // Flat planes stay flat at any size
+        let scaled = scale(
+            &frame,
+            Size {
+                width: 13,
+                height: 5,
+            },
+            filter,
+        )
+        .unwrap();
+        assert_eq!(scaled.data_y.len(), 13 * 5);
+        assert!(scaled.data_cb.iter().all(|&value| value == 77));
+        assert!(scaled.data_cr.iter().all(|&value| value == 200));
+    }
+
+    // Empty sizes are rejected rather than giving filters with no taps
+    assert!(matches!(Size::from_str("0x8"), Err(Error::EmptySize)));
+    let empty = Size {
+        width: 8,
+        height: 0,
+    };
+    assert!(matches!(
+        scale(&frame, empty, ScaleFilter::Bicubic),
+        Err(Error::EmptySize)
+    ));
+}
+
+#[test]
+fn resizes_odd_sized_420_frames() {
+    use crate::yuv4mpeg2::{ColorSpace, Header};
+
+    // Every plane must match the sizes the decoder expects, which round chroma up
+    let check_sizes = |frame: &Frame, width, height| {
+        assert_eq!((frame.width, frame.height), (width, height));
+        assert_eq!(frame.data_y.len(), width * height);
+        let chroma_len = width.div_ceil(2) * height.div_ceil(2);
+        assert_eq!(frame.data_cb.len(), chroma_len);
+        assert_eq!(frame.data_cr.len(), chroma_len);
+        let header = Header {
+            width,
+            height,
+            color_space: ColorSpace::C420jpeg,
+            ..Header::default()
+        };
+        assert_eq!(frame.to_vec().len(), header.frame_bytes_length());
+    };
+
+    let frame = Frame {
+        width: 15,
+        height: 9,
+        color_space: ColorSpace::C420jpeg,
+        data_y: (0..135).map(|i| i as u8).collect(),
+        data_cb: (0..40).collect(),
+        data_cr: (100..140).collect(),
+    };
+    let scaled = scale(
+        &frame,
+        Size {
+            width: 13,
+            height: 5,
+        },
+        ScaleFilter::Bicubic,
+    )
+    .unwrap();
+    check_sizes(&scaled, 13, 5);
+
+    let padded = pad(&frame, 7).unwrap();
+    check_sizes(&padded, 21, 14);
+    assert_eq!(padded.data_cb[10], frame.data_cb[7]); // last chroma column replicated
+
+    let cropped = crop(&frame, Rect::from_str("6x4+8+4").unwrap()).unwrap();
+    check_sizes(&cropped, 6, 4);
+    assert_eq!(cropped.data_cb, vec![20, 21, 22, 28, 29, 30]);
+}
@@ -0,0 +1,600 @@
+use crate::wavelet::{self, Wavelet};
+use crate::yuv4mpeg2::{ColorSpace, Frame};
+
+// Embedded coding of wavelet coefficients by set partitioning in hierarchical trees (SPIHT, Said
+// and Pearlman 1996). Bit planes are sent from the most significant down, with the planes of the
+// frame interleaved, so the stream can be cut off after any byte and still decodes to the best
+// picture that many bytes allow.
+//
+// The stream is one byte giving the number of bit planes, then the bits of the sorting and
+// refinement passes, most significant bit of each byte first
+
+// Coefficients are weighted by the norm of their subband's basis functions, so that every bit
+// plane lowers the squared error of the picture by the same amount wherever it is spent, and
+// kept to this many fractional bits
+const FRACTION_BITS: u32 = 2;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Plane {0} has {1} samples, which doesn't match its {2}x{3} dimensions")]
+    PlaneSize(usize, usize, usize, usize),
+}
+
+// Codes the frame's coefficients, stopping once the stream is max_bytes long. The stream is only
+// shorter if every bit plane fits
+pub fn encode(
+    frame: &Frame,
+    wavelet: Wavelet,
+    levels: usize,
+    max_bytes: usize,
+) -> Result<Vec<u8>, Error> {
+    // The decoder only knows the declared size, so a plane of any other size can't be rebuilt
+    for (index, (values, width, height)) in frame.planes().into_iter().enumerate() {
+        if values.len() != width * height {
+            return Err(Error::PlaneSize(index, values.len(), width, height));
+        }
+    }
+    let mut planes = frame.planes().map(|(values, width, height)| {
+        PlaneCoder::from_values(values, width, height, wavelet, levels)
+    });
+    let largest = planes
+        .iter()
+        .flat_map(|plane| plane.magnitudes.iter())
+        .max()
+        .copied()
+        .unwrap_or(0);
+    let bit_planes = u32::BITS - largest.leading_zeros();
+
+    if max_bytes == 0 {
+        return Ok(Vec::new());
+    }
+    let mut writer = BitWriter {
+        bytes: vec![bit_planes as u8],
+        bit_count: 0,
+        max_bits: (max_bytes - 1).saturating_mul(8),
+    };
+    code_planes(&mut planes, bit_planes, &mut writer);
+    Ok(writer.bytes)
+}
+
+// Rebuilds a frame of the given size from a stream, or from any prefix of one
+pub fn decode(
+    stream: &[u8],
+    width: usize,
+    height: usize,
+    color_space: ColorSpace,
+    wavelet: Wavelet,
+    levels: usize,
+) -> Frame {
+    let blank = Frame {
+        width,
+        height,
+        color_space,
+        data_y: Vec::new(),
+        data_cb: Vec::new(),
+        data_cr: Vec::new(),
+    };
+    let [y, cb, cr] = blank.planes().map(|(_, width, height)| (width, height));
+    let mut planes = [y, cb, cr].map(|(width, height)| {
+        PlaneCoder::new(vec![0.; width * height], width, height, wavelet, levels)
+    });
+    if let Some((&bit_planes, bits)) = stream.split_first() {
+        let mut reader = BitReader {
+            bytes: bits,
+            bit_count: 0,
+        };
+        // Magnitudes are 32-bit, so a corrupt stream can't ask for more planes than that
+        let bit_planes = (bit_planes as u32).min(u32::BITS);
+        code_planes(&mut planes, bit_planes, &mut reader);
+    }
+
+    let [y, cb, cr] = planes.map(|plane| plane.reconstruct(wavelet, levels));
+    Frame {
+        data_y: y,
+        data_cb: cb,
+        data_cr: cr,
+        ..blank
+    }
+}
+
+// Codes each frame into at most the given number of bytes and decodes it again
+pub fn quantise_frame(
+    frame: Frame,
+    wavelet: Wavelet,
+    levels: usize,
+    max_bytes: usize,
+) -> Result<Frame, Error> {
+    let stream = encode(&frame, wavelet, levels, max_bytes)?;
+    Ok(decode(
+        &stream,
+        frame.width,
+        frame.height,
+        frame.color_space,
+        wavelet,
+        levels,
+    ))
+}
+
+// The encoder and decoder run the same passes, so that they make the same decisions. Each bit
+// is written by the encoder and read by the decoder, which ignores the value it is given
+trait BitCoder {
+    // None once the stream is used up
+    fn code(&mut self, bit: bool) -> Option<bool>;
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit_count: usize,
+    max_bits: usize,
+}
+
+impl BitCoder for BitWriter {
+    fn code(&mut self, bit: bool) -> Option<bool> {
+        if self.bit_count == self.max_bits {
+            return None;
+        }
+        if self.bit_count.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bit_count % 8);
+        }
+        self.bit_count += 1;
+        Some(bit)
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    bit_count: usize,
+}
+
+impl BitCoder for BitReader<'_> {
+    fn code(&mut self, _: bool) -> Option<bool> {
+        let byte = self.bytes.get(self.bit_count / 8)?;
+        let bit = byte & (0x80 >> (self.bit_count % 8)) != 0;
+        self.bit_count += 1;
+        Some(bit)
+    }
+}
+
+// Sorting and refinement passes over every plane for each bit plane, until the stream runs out
+fn code_planes(planes: &mut [PlaneCoder; 3], bit_planes: u32, coder: &mut impl BitCoder) {
+    for bit_plane in (0..bit_planes).rev() {
+        for plane in planes.iter_mut() {
+            let refined = plane.significant.len();
+            if plane.sorting_pass(bit_plane, coder).is_none()
+                || plane.refinement_pass(bit_plane, refined, coder).is_none()
+            {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Set {
+    // All descendants of a coefficient
+    Descendants,
+    // Descendants other than the children
+    Grandchildren,
+}
+
+struct PlaneCoder {
+    tree: Tree,
+    // Weighted fixed-point magnitudes and signs, which the decoder learns bit by bit
+    magnitudes: Vec<u32>,
+    negative: Vec<bool>,
+    // Largest magnitude among each coefficient's descendants, and among its grandchildren and
+    // below. Only known to the encoder
+    descendant_max: Vec<u32>,
+    grandchild_max: Vec<u32>,
+    // Weight each coefficient was multiplied by
+    weights: Vec<f64>,
+    // Lists of insignificant coefficients, insignificant sets and significant coefficients
+    insignificant: Vec<usize>,
+    sets: Vec<(usize, Set)>,
+    significant: Vec<usize>,
+    // Magnitude bits coded so far, and the lowest bit plane coded for each coefficient
+    known: Vec<u32>,
+    lowest_plane: Vec<u32>,
+}
+
+impl PlaneCoder {
+    fn from_values(
+        values: &[u8],
+        width: usize,
+        height: usize,
+        wavelet: Wavelet,
+        levels: usize,
+    ) -> Self {
+        let mut coefficients = values
+            .iter()
+            .map(|&value| value as f64 - 128.)
+            .collect::<Vec<_>>();
+        wavelet::forward_2d(&mut coefficients, width, height, wavelet, levels);
+
+        PlaneCoder::new(coefficients, width, height, wavelet, levels)
+    }
+
+    // Coefficients are zero for the decoder, which only uses the plane's shape
+    fn new(
+        coefficients: Vec<f64>,
+        width: usize,
+        height: usize,
+        wavelet: Wavelet,
+        levels: usize,
+    ) -> Self {
+        let tree = Tree::new(width, height, levels);
+        let mut weights = vec![0.; width * height];
+        let bands = wavelet::subbands(width, height, levels);
+        for ((xs, ys), norm) in bands.iter().zip(wavelet::subband_norms(wavelet, levels)) {
+            for y in ys.clone() {
+                weights[y * width + xs.start..y * width + xs.end].fill(norm);
+            }
+        }
+        let scale = (1 << FRACTION_BITS) as f64;
+        let magnitudes = coefficients
+            .iter()
+            .zip(&weights)
+            .map(|(coefficient, weight)| (coefficient.abs() * weight * scale).round() as u32)
+            .collect::<Vec<_>>();
+        let negative = coefficients.iter().map(|&value| value < 0.).collect();
+
+        // Children are always in finer subbands, which come first
+        let (mut descendant_max, mut grandchild_max) =
+            (vec![0; width * height], vec![0; width * height]);
+        for (xs, ys) in &bands {
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    let index = y * width + x;
+                    for child in tree.children(index) {
+                        descendant_max[index] = descendant_max[index]
+                            .max(magnitudes[child])
+                            .max(descendant_max[child]);
+                        grandchild_max[index] = grandchild_max[index].max(descendant_max[child]);
+                    }
+                }
+            }
+        }
+
+        // Coding starts from the roots of the tree
+        let (root_width, root_height) = tree.sizes[tree.sizes.len() - 1];
+        let roots = (0..root_height)
+            .flat_map(|y| (0..root_width).map(move |x| y * width + x))
+            .collect::<Vec<_>>();
+        let sets = roots
+            .iter()
+            .filter(|&&index| tree.children(index).next().is_some())
+            .map(|&index| (index, Set::Descendants))
+            .collect();
+
+        PlaneCoder {
+            tree,
+            magnitudes,
+            negative,
+            descendant_max,
+            grandchild_max,
+            weights,
+            insignificant: roots,
+            sets,
+            significant: Vec::new(),
+            known: vec![0; width * height],
+            lowest_plane: vec![0; width * height],
+        }
+    }
+
+    fn sorting_pass(&mut self, bit_plane: u32, coder: &mut impl BitCoder) -> Option<()> {
+        let threshold = 1 << bit_plane;
+        let insignificant = std::mem::take(&mut self.insignificant);
+        for index in insignificant {
+            if !self.code_coefficient(index, bit_plane, coder)? {
+                self.insignificant.push(index);
+            }
+        }
+
+        // Sets found significant are split, and any new sets are appended and tested in turn
+        let mut remaining = Vec::new();
+        let mut position = 0;
+        while position < self.sets.len() {
+            let (index, set) = self.sets[position];
+            position += 1;
+            match set {
+                Set::Descendants => {
+                    if !coder.code(self.descendant_max[index] >= threshold)? {
+                        remaining.push((index, set));
+                        continue;
+                    }
+                    for child in self.tree.children(index) {
+                        if !self.code_coefficient(child, bit_plane, coder)? {
+                            self.insignificant.push(child);
+                        }
+                    }
+                    if self.tree.has_grandchildren(index) {
+                        self.sets.push((index, Set::Grandchildren));
+                    }
+                }
+                Set::Grandchildren => {
+                    if !coder.code(self.grandchild_max[index] >= threshold)? {
+                        remaining.push((index, set));
+                        continue;
+                    }
+                    for child in self.tree.children(index) {
+                        self.sets.push((child, Set::Descendants));
+                    }
+                }
+            }
+        }
+        self.sets = remaining;
+        Some(())
+    }
+
+    // Codes whether a coefficient becomes significant at this bit plane, and if so its sign
+    fn code_coefficient(
+        &mut self,
+        index: usize,
+        bit_plane: u32,
+        coder: &mut impl BitCoder,
+    ) -> Option<bool> {
+        let threshold = 1 << bit_plane;
+        let significant = coder.code(self.magnitudes[index] >= threshold)?;
+        if significant {
+            self.negative[index] = coder.code(self.negative[index])?;
+            self.known[index] = threshold;
+            self.lowest_plane[index] = bit_plane;
+            self.significant.push(index);
+        }
+        Some(significant)
+    }
+
+    // Codes the next bit of every coefficient that was already significant before this pass
+    fn refinement_pass(
+        &mut self,
+        bit_plane: u32,
+        count: usize,
+        coder: &mut impl BitCoder,
+    ) -> Option<()> {
+        let threshold = 1 << bit_plane;
+        for position in 0..count {
+            let index = self.significant[position];
+            if coder.code(self.magnitudes[index] & threshold != 0)? {
+                self.known[index] |= threshold;
+            }
+            self.lowest_plane[index] = bit_plane;
+        }
+        Some(())
+    }
+
+    // Places each coefficient in the middle of the range its coded bits allow, and inverse
+    // transforms the plane
+    fn reconstruct(&self, wavelet: Wavelet, levels: usize) -> Vec<u8> {
+        let scale = (1 << FRACTION_BITS) as f64;
+        let mut coefficients = (0..self.known.len())
+            .map(|index| {
+                let known = self.known[index];
+                if known == 0 {
+                    return 0.;
+                }
+                let magnitude = known as f64 + ((1u32 << self.lowest_plane[index]) / 2) as f64;
+                let sign = if self.negative[index] { -1. } else { 1. };
+                sign * magnitude / (self.weights[index] * scale)
+            })
+            .collect::<Vec<_>>();
+        let (width, height) = (self.tree.width, self.tree.height);
+        wavelet::inverse_2d(&mut coefficients, width, height, wavelet, levels);
+        coefficients
+            .into_iter()
+            .map(|value| (value + 128.).round().clamp(0., 255.) as u8)
+            .collect()
+    }
+}
+
+// Parent-child relationships between coefficients in the layout of wavelet::forward_2d(). Each
+// coefficient of the coarsest low-pass band has a child at the same position in each of the
+// coarsest detail bands, and each detail coefficient has up to four children at twice its
+// position in the next finer band of the same orientation
+struct Tree {
+    width: usize,
+    height: usize,
+    // Size of the low-pass region before each level, and after the last
+    sizes: Vec<(usize, usize)>,
+}
+
+impl Tree {
+    fn new(width: usize, height: usize, levels: usize) -> Self {
+        // The tree stops at the last level that halves both dimensions, as after that some
+        // subbands are empty and their finer bands would have no parents. Any coarser
+        // coefficients are roots of the tree
+        let mut sizes = vec![(width, height)];
+        while sizes.len() <= levels {
+            let (width, height) = sizes[sizes.len() - 1];
+            if width < 2 || height < 2 {
+                break;
+            }
+            sizes.push((width.div_ceil(2), height.div_ceil(2)));
+        }
+        Tree {
+            width,
+            height,
+            sizes,
+        }
+    }
+
+    // Up to nine children, as with odd sizes a band can be one longer than twice its parent band,
+    // and the last coefficient in each direction then takes the extra child
+    fn children(&self, index: usize) -> impl Iterator<Item = usize> {
+        let (x, y) = (index % self.width.max(1), index / self.width.max(1));
+        let levels = self.sizes.len() - 1;
+        let mut children = [None; 9];
+        let (low_width, low_height) = self.sizes[levels];
+        if levels == 0 {
+            // No transform, so no tree
+        } else if x < low_width && y < low_height {
+            let (end_x, end_y) = self.sizes[levels - 1];
+            let candidates = [
+                (x + low_width, y),
+                (x, y + low_height),
+                (x + low_width, y + low_height),
+            ];
+            for (child, (x, y)) in children.iter_mut().zip(candidates) {
+                if x < end_x && y < end_y {
+                    *child = Some(y * self.width + x);
+                }
+            }
+        } else {
+            // The coefficient is in the detail bands of the coarsest level whose input contains it
+            let level = (1..=levels)
+                .rev()
+                .find(|&level| x < self.sizes[level - 1].0 && y < self.sizes[level - 1].1)
+                .expect("every coefficient is inside the full-size region");
+            if level > 1 {
+                let axis = |position: usize, sizes: [usize; 3]| {
+                    // Low or high half, then the start and length of the band and the finer band
+                    let [size, input_size, finer_input_size] = sizes;
+                    let (start, length, finer_start, finer_length) = match position >= size {
+                        false => (0, size, 0, input_size),
+                        true => (
+                            size,
+                            input_size - size,
+                            input_size,
+                            finer_input_size - input_size,
+                        ),
+                    };
+                    let band_position = position - start;
+                    let count = if band_position + 1 == length { 3 } else { 2 };
+                    (2 * band_position..2 * band_position + count)
+                        .filter(move |&child| child < finer_length)
+                        .map(move |child| finer_start + child)
+                };
+                let xs = axis(x, [0, 1, 2].map(|offset| self.sizes[level - offset].0));
+                let ys = axis(y, [0, 1, 2].map(|offset| self.sizes[level - offset].1));
+                let positions = ys.flat_map(|y| xs.clone().map(move |x| y * self.width + x));
+                for (child, index) in children.iter_mut().zip(positions) {
+                    *child = Some(index);
+                }
+            }
+        }
+        children.into_iter().flatten()
+    }
+
+    fn has_grandchildren(&self, index: usize) -> bool {
+        self.children(index)
+            .any(|child| self.children(child).next().is_some())
+    }
+}
+
+#[test]
+fn every_coefficient_has_one_parent() {
+    for (width, height, levels) in [
+        (16, 16, 3),
+        (17, 9, 3),
+        (5, 3, 4),
+        (8, 1, 2),
+        (48, 40, 4),
+        (23, 37, 5),
+    ] {
+        let tree = Tree::new(width, height, levels);
+        let mut parents = vec![0; width * height];
+        for index in 0..width * height {
+            for child in tree.children(index) {
+                parents[child] += 1;
+            }
+        }
+        // Only the roots have no parents
+        let (root_width, root_height) = tree.sizes[tree.sizes.len() - 1];
+        for (index, &count) in parents.iter().enumerate() {
+            let root = index % width < root_width && index / width < root_height;
+            assert_eq!(
+                count,
+                if root { 0 } else { 1 },
+                "{width}x{height} at {index}"
+            );
+        }
+    }
+}
+
+#[test]
+fn truncates_to_any_size() {
+    use crate::{
+        generate::{generate_frame, Pattern},
+        metrics::psnr,
+        yuv4mpeg2::Header,
+    };
+
+    let header = Header {
+        width: 48,
+        height: 40,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let frame = generate_frame(Pattern::ZonePlate, &header, 0);
+    let decode_frame =
+        |stream: &[u8], wavelet| decode(stream, 48, 40, ColorSpace::C420jpeg, wavelet, 4);
+
+    for wavelet in [Wavelet::Cdf97, Wavelet::LeGall53] {
+        let full = encode(&frame, wavelet, 4, usize::MAX).unwrap();
+        let restored = decode_frame(&full, wavelet);
+        let full_psnr = psnr(&frame.data_y, &restored.data_y);
+        dbg!(wavelet, full.len(), full_psnr);
+        assert!(full_psnr > 45.);
+
+        // Cutting the full stream short is the same as encoding to that size, and quality only
+        // improves as bytes are added
+        let mut previous_psnr = 0.;
+        for size in [0, 1, 2, 50, 200, 800, 2000] {
+            let stream = encode(&frame, wavelet, 4, size).unwrap();
+            assert_eq!(stream.len(), size);
+            assert_eq!(stream, full[..size]);
+            let decoded = decode_frame(&stream, wavelet);
+            assert_eq!(decoded.to_vec().len(), frame.to_vec().len());
+            let size_psnr = psnr(&frame.data_y, &decoded.data_y);
+            assert!(
+                size_psnr >= previous_psnr,
+                "{size}: {size_psnr} < {previous_psnr}"
+            );
+            previous_psnr = size_psnr;
+        }
+    }
+}
+
+#[test]
+fn codes_fields_and_corrupt_streams() {
+    use crate::{
+        generate::{generate_frame, Pattern},
+        interlace,
+        metrics::psnr,
+        yuv4mpeg2::{Header, InterlaceMode},
+    };
+
+    // The fields of a 4:2:0 frame 10 lines high have an odd number of chroma lines
+    let header = Header {
+        width: 16,
+        height: 10,
+        interlace_mode: InterlaceMode::It,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let frame = generate_frame(Pattern::ZonePlate, &header, 0);
+    let quantised = interlace::try_map_fields(&frame, |field| {
+        quantise_frame(field, Wavelet::LeGall53, 5, 100000)
+    })
+    .unwrap();
+    assert_eq!(quantised.to_vec().len(), frame.to_vec().len());
+    assert!(psnr(&frame.data_cb, &quantised.data_cb) > 40.);
+
+    // Any number of bit planes decodes to something
+    for bit_planes in [31, 32, 33, 255] {
+        let mut stream = encode(&frame, Wavelet::Cdf97, 3, 100).unwrap();
+        stream[0] = bit_planes;
+        let decoded = decode(&stream, 16, 10, ColorSpace::C420jpeg, Wavelet::Cdf97, 3);
+        assert_eq!(decoded.to_vec().len(), frame.to_vec().len());
+    }
+
+    // Planes that don't match the frame's size are an error rather than a panic
+    let mut short = frame.clone();
+    short.data_cr.pop();
+    assert!(matches!(
+        encode(&short, Wavelet::Cdf97, 3, 100),
+        Err(Error::PlaneSize(2, 39, 8, 5))
+    ));
+}
@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+
+use crate::{
+    interlace::{merge_fields, split_fields},
+    yuv4mpeg2::{Frame, Header, InterlaceMode},
+};
+
+// 3:2 pulldown spreads every 4 film frames over 5 video frames
+const CYCLE_LENGTH: usize = 5;
+
+// Number of frames at the start of the input that are searched for a cadence
+pub const PROBE_FRAMES: usize = 6 * CYCLE_LENGTH;
+
+// A frame is only taken to repeat its predecessor when it changes by at most this fraction of the
+// next smallest change in its cycle
+const DUPLICATE_RATIO: u64 = 4;
+
+// Reverses 3:2 pulldown, turning telecined 29.97 fps video back into 23.976 fps film frames.
+//
+// Field matching: the first field of each frame is kept and paired with whichever of the
+// previous, current or next frame's second field produces the least combing, which
+// reassembles the film frames that were split across video frames.
+// Decimation: the cadence is then detected in each cycle of five matched frames by finding
+// the frame that clearly duplicates its predecessor, which is dropped. Once a cadence is locked
+// its position is kept through cycles with no clear duplicate (such as static scenes), so exactly
+// one frame in five is dropped. Before that, frames are passed through unchanged.
+pub struct InverseTelecine<I: Iterator<Item = Frame>> {
+    source: I,
+    keep_top: bool,
+    // Index within each cycle of the repeated frame, once a cadence is locked
+    phase: Option<usize>,
+    previous: Option<Frame>,
+    current: Option<Frame>,
+    last_matched: Option<Frame>,
+    cycle: Vec<Frame>,
+    output: VecDeque<Frame>,
+}
+
+impl<I: Iterator<Item = Frame>> InverseTelecine<I> {
+    // The phase is the index within each cycle of the repeated frame, if the cadence is known
+    pub fn new(source: I, interlace_mode: InterlaceMode, phase: Option<usize>) -> Self {
+        InverseTelecine {
+            source,
+            keep_top: interlace_mode != InterlaceMode::Ib,
+            phase,
+            previous: None,
+            current: None,
+            last_matched: None,
+            cycle: Vec::with_capacity(CYCLE_LENGTH),
+            output: VecDeque::new(),
+        }
+    }
+
+    // Pairs the kept field of the current frame with the best matching opposite field
+    fn match_fields(&self, current: &Frame, next: Option<&Frame>) -> Frame {
+        let (current_top, current_bottom) = split_fields(current);
+        let candidates = [Some(current), self.previous.as_ref(), next]
+            .into_iter()
+            .flatten()
+            .map(|candidate| {
+                let (top, bottom) = split_fields(candidate);
+                if self.keep_top {
+                    merge_fields(&current_top, &bottom, current.height)
+                } else {
+                    merge_fields(&top, &current_bottom, current.height)
+                }
+            });
+
+        // The current frame is considered first so that it wins ties
+        candidates
+            .map(|frame| (combing(&frame), frame))
+            .reduce(|best, candidate| {
+                if candidate.0 < best.0 {
+                    candidate
+                } else {
+                    best
+                }
+            })
+            .map(|(_, frame)| frame)
+            .unwrap_or_else(|| current.clone())
+    }
+
+    // Index of the frame in the cycle that repeats the frame before it, if one differs far less
+    // from its predecessor than every other frame does. Cycles without motion have none
+    fn find_duplicate(&self) -> Option<usize> {
+        let mut predecessor = self.last_matched.as_ref();
+        let mut changes = Vec::with_capacity(CYCLE_LENGTH);
+        for (index, frame) in self.cycle.iter().enumerate() {
+            changes.push((
+                predecessor.map_or(u64::MAX, |p| difference(p, frame)),
+                index,
+            ));
+            predecessor = Some(frame);
+        }
+        changes.sort();
+        match changes[..] {
+            [(smallest, index), (next, _), ..]
+                if next > 0 && smallest.saturating_mul(DUPLICATE_RATIO) <= next =>
+            {
+                Some(index)
+            }
+            _ => None,
+        }
+    }
+
+    // Drops the repeated frame from the cycle once the cadence is locked, following the
+    // cadence to any clear duplicate at a new position
+    fn decimate(&mut self) {
+        if let Some(index) = self.find_duplicate() {
+            self.phase = Some(index);
+        }
+
+        self.last_matched = self.cycle.last().cloned();
+        for (index, frame) in self.cycle.drain(..).enumerate() {
+            if Some(index) != self.phase {
+                self.output.push_back(frame);
+            }
+        }
+    }
+}
+
+// Finds the position of the repeated frame in the 3:2 cadence of the given frames, or None if
+// they don't look telecined
+pub fn detect_phase(frames: &[Frame], interlace_mode: InterlaceMode) -> Option<usize> {
+    let mut detector = InverseTelecine::new(frames.iter().cloned(), interlace_mode, None);
+    detector.by_ref().for_each(drop);
+    detector.phase
+}
+
+impl<I: Iterator<Item = Frame>> Iterator for InverseTelecine<I> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(frame) = self.output.pop_front() {
+                return Some(frame);
+            }
+
+            let next = self.source.next();
+            match self.current.take() {
+                Some(current) => {
+                    let matched = self.match_fields(&current, next.as_ref());
+                    self.previous = Some(current);
+                    self.current = next;
+                    self.cycle.push(matched);
+                    if self.cycle.len() == CYCLE_LENGTH {
+                        self.decimate();
+                    }
+                }
+                None => match next {
+                    Some(frame) => self.current = Some(frame),
+                    None => {
+                        // An incomplete cycle at the end is passed through without decimation
+                        self.output.extend(self.cycle.drain(..));
+                        return self.output.pop_front();
+                    }
+                },
+            }
+        }
+    }
+}
+
+// Header of the inverse telecined output once a cadence is locked, which is progressive with one
+// in every five frames removed
+pub fn output_header(header: &Header) -> Header {
+    let mut output_header = *header;
+    output_header.interlace_mode = InterlaceMode::Ip;
+    let numerator = header.frame_rate_numerator * (CYCLE_LENGTH - 1);
+    let denominator = header.frame_rate_denominator * CYCLE_LENGTH;
+    let divisor = gcd(numerator, denominator).max(1);
+    output_header.frame_rate_numerator = numerator / divisor;
+    output_header.frame_rate_denominator = denominator / divisor;
+    output_header
+}
+
+// Measures how strongly each luma line differs from both lines either side of it in the
+// same direction, which is the signature of fields from different instants being woven together
+fn combing(frame: &Frame) -> u64 {
+    let width = frame.width;
+    let mut total = 0;
+    for row in 1..frame.height.saturating_sub(1) {
+        for x in 0..width {
+            let above = frame.data_y[(row - 1) * width + x] as i64;
+            let current = frame.data_y[row * width + x] as i64;
+            let below = frame.data_y[(row + 1) * width + x] as i64;
+            total += ((above - current) * (below - current)).max(0) as u64;
+        }
+    }
+    total
+}
+
+// Sum of absolute differences between the luma planes of two frames
+fn difference(a: &Frame, b: &Frame) -> u64 {
+    a.data_y
+        .iter()
+        .zip(&b.data_y)
+        .map(|(a, b)| a.abs_diff(*b) as u64)
+        .sum()
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[test]
+fn recovers_film_frames_from_pulldown() {
+    use crate::yuv4mpeg2::ColorSpace;
+
+    const WIDTH: usize = 8;
+    const HEIGHT: usize = 8;
+    let film_frames = (0..8)
+        .map(|k| Frame {
+            width: WIDTH,
+            height: HEIGHT,
+            color_space: ColorSpace::C444,
+            data_y: (0..WIDTH * HEIGHT)
+                .map(|i| (25 * k + i / WIDTH + i % WIDTH) as u8)
+                .collect(),
+            data_cb: vec![k as u8; WIDTH * HEIGHT],
+            data_cr: vec![128; WIDTH * HEIGHT],
+        })
+        .collect::<Vec<_>>();
+
+    // Film frames A B C D become video frames AA BB BC CD DD (top field / bottom field)
+    let weave = |top: &Frame, bottom: &Frame| {
+        merge_fields(&split_fields(top).0, &split_fields(bottom).1, top.height)
+    };
+    let telecined = film_frames
+        .chunks(4)
+        .flat_map(|f| {
+            [
+                weave(&f[0], &f[0]),
+                weave(&f[1], &f[1]),
+                weave(&f[1], &f[2]),
+                weave(&f[2], &f[3]),
+                weave(&f[3], &f[3]),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(detect_phase(&telecined, InterlaceMode::It), Some(2));
+    let recovered =
+        InverseTelecine::new(telecined.into_iter(), InterlaceMode::It, Some(2)).collect::<Vec<_>>();
+    assert_eq!(recovered.len(), film_frames.len());
+    for (recovered, original) in recovered.iter().zip(&film_frames) {
+        assert_eq!(recovered.to_vec(), original.to_vec());
+    }
+
+    let header = Header {
+        frame_rate_numerator: 30000,
+        frame_rate_denominator: 1001,
+        ..Header::default()
+    };
+    let header = output_header(&header);
+    assert_eq!(
+        (header.frame_rate_numerator, header.frame_rate_denominator),
+        (24000, 1001)
+    );
+
+    // Progressive frames that all move have no cadence, so nothing is dropped
+    assert_eq!(detect_phase(&film_frames, InterlaceMode::It), None);
+    let passed =
+        InverseTelecine::new(film_frames.clone().into_iter(), InterlaceMode::It, None).count();
+    assert_eq!(passed, film_frames.len());
+}
@@ -0,0 +1,440 @@
+use crate::yuv4mpeg2::Frame;
+
+// Multi-level 2D discrete wavelet transforms by lifting, with symmetric extension at the edges
+// as in JPEG 2000. Coefficients are laid out in place: after each level the low-pass half of
+// every row and column is on the top left, and the next level transforms only that part
+
+// More levels than this would only transform single samples on any practical frame size
+pub const MAX_LEVELS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Wavelet {
+    /// Irreversible Cohen-Daubechies-Feauveau 9/7 wavelet, as in lossy JPEG 2000
+    Cdf97,
+    /// Reversible LeGall 5/3 wavelet with integer lifting, as in lossless JPEG 2000
+    LeGall53,
+}
+
+// Lifting coefficients of the CDF 9/7 wavelet
+const ALPHA: f64 = -1.586134342059924;
+const BETA: f64 = -0.052980118572961;
+const GAMMA: f64 = 0.882911075530934;
+const DELTA: f64 = 0.443506852043971;
+// Gain of the low-pass band after lifting. The bands are rescaled so the transform is close to
+// orthonormal, like the DCT, rather than by 1/K and K/2 as in JPEG 2000
+const K: f64 = 1.230174104914001;
+
+// Quantises each plane in the wavelet domain. Each subband's step is the plane's step divided by
+// the norm of the subband's synthesis basis functions, so that every subband adds about the same
+// error per coefficient to the picture. A step of zero skips quantisation, which makes the 5/3
+// wavelet lossless
+pub fn quantise_frame(
+    frame: Frame,
+    wavelet: Wavelet,
+    levels: usize,
+    luma_step: f64,
+    chroma_step: f64,
+) -> Frame {
+    let norms = subband_norms(wavelet, levels);
+    frame.map_planes(|index, values, width, height| {
+        let step = match index {
+            0 => luma_step,
+            _ => chroma_step,
+        };
+        let mut coefficients = values
+            .iter()
+            .map(|&value| value as f64 - 128.)
+            .collect::<Vec<_>>();
+        forward_2d(&mut coefficients, width, height, wavelet, levels);
+        if step > 0. {
+            for (subband, norm) in subbands(width, height, levels).into_iter().zip(&norms) {
+                quantise_subband(&mut coefficients, width, subband, step / norm);
+            }
+        }
+        inverse_2d(&mut coefficients, width, height, wavelet, levels);
+        coefficients
+            .into_iter()
+            .map(|value| (value + 128.).round().clamp(0., 255.) as u8)
+            .collect()
+    })
+}
+
+// Region of a subband in the coefficient layout: x and y ranges
+type Subband = (std::ops::Range<usize>, std::ops::Range<usize>);
+
+// Every subband of a plane, from the finest level to the coarsest, with the HL, LH and HH
+// (horizontally high, vertically high and diagonal) bands of each level and the final LL band last
+pub fn subbands(width: usize, height: usize, levels: usize) -> Vec<Subband> {
+    let mut bands = Vec::with_capacity(3 * levels + 1);
+    let (mut level_width, mut level_height) = (width, height);
+    for _ in 0..levels {
+        let (low_width, low_height) = (level_width.div_ceil(2), level_height.div_ceil(2));
+        bands.push((low_width..level_width, 0..low_height));
+        bands.push((0..low_width, low_height..level_height));
+        bands.push((low_width..level_width, low_height..level_height));
+        (level_width, level_height) = (low_width, low_height);
+    }
+    bands.push((0..level_width, 0..level_height));
+    bands
+}
+
+fn quantise_subband(coefficients: &mut [f64], width: usize, (xs, ys): Subband, step: f64) {
+    for y in ys {
+        for value in &mut coefficients[y * width + xs.start..y * width + xs.end] {
+            *value = (*value / step).round() * step;
+        }
+    }
+}
+
+// Norms of the 2D synthesis basis functions of each subband, in the order of subbands(). The
+// transform is separable, so each is the product of the norms of a row and a column function,
+// which are measured by inverse transforming an impulse in a long 1D signal
+pub(crate) fn subband_norms(wavelet: Wavelet, levels: usize) -> Vec<f64> {
+    let length = 1 << (levels + 5);
+    // Large enough that the rounding in the reversible wavelet is negligible
+    let amplitude = (1 << 20) as f64;
+    let norm_1d = |level: usize, high: bool| {
+        let mut signal = vec![0.; length];
+        let band_start = match high {
+            true => length >> level,
+            false => 0,
+        };
+        signal[band_start + (length >> (level + 1))] = amplitude;
+        for level in (0..level).rev() {
+            inverse_1d(&mut signal[..length >> level], wavelet);
+        }
+        signal.iter().map(|value| value * value).sum::<f64>().sqrt() / amplitude
+    };
+
+    let mut norms = Vec::with_capacity(3 * levels + 1);
+    for level in 1..=levels {
+        let (low, high) = (norm_1d(level, false), norm_1d(level, true));
+        norms.extend([high * low, low * high, high * high]);
+    }
+    let low = norm_1d(levels, false);
+    norms.push(low * low);
+    norms
+}
+
+// Transforms the rows and then the columns of the low-pass region, once for each level
+pub fn forward_2d(
+    coefficients: &mut [f64],
+    width: usize,
+    height: usize,
+    wavelet: Wavelet,
+    levels: usize,
+) {
+    let (mut level_width, mut level_height) = (width, height);
+    for _ in 0..levels {
+        transform_rows(coefficients, width, level_width, level_height, |row| {
+            forward_1d(row, wavelet)
+        });
+        transform_columns(coefficients, width, level_width, level_height, |column| {
+            forward_1d(column, wavelet)
+        });
+        (level_width, level_height) = (level_width.div_ceil(2), level_height.div_ceil(2));
+    }
+}
+
+pub fn inverse_2d(
+    coefficients: &mut [f64],
+    width: usize,
+    height: usize,
+    wavelet: Wavelet,
+    levels: usize,
+) {
+    let sizes = (0..levels)
+        .scan((width, height), |size, _| {
+            let level_size = *size;
+            *size = (size.0.div_ceil(2), size.1.div_ceil(2));
+            Some(level_size)
+        })
+        .collect::<Vec<_>>();
+    for &(level_width, level_height) in sizes.iter().rev() {
+        transform_columns(coefficients, width, level_width, level_height, |column| {
+            inverse_1d(column, wavelet)
+        });
+        transform_rows(coefficients, width, level_width, level_height, |row| {
+            inverse_1d(row, wavelet)
+        });
+    }
+}
+
+fn transform_rows(
+    coefficients: &mut [f64],
+    stride: usize,
+    width: usize,
+    height: usize,
+    transform: impl Fn(&mut [f64]),
+) {
+    for y in 0..height {
+        transform(&mut coefficients[y * stride..y * stride + width]);
+    }
+}
+
+fn transform_columns(
+    coefficients: &mut [f64],
+    stride: usize,
+    width: usize,
+    height: usize,
+    transform: impl Fn(&mut [f64]),
+) {
+    let mut column = vec![0.; height];
+    for x in 0..width {
+        for (y, value) in column.iter_mut().enumerate() {
+            *value = coefficients[y * stride + x];
+        }
+        transform(&mut column);
+        for (y, value) in column.iter().enumerate() {
+            coefficients[y * stride + x] = *value;
+        }
+    }
+}
+
+// One level of the 1D transform in place, leaving the low-pass coefficients in the first half
+// (rounded up) and the high-pass coefficients in the rest
+pub fn forward_1d(signal: &mut [f64], wavelet: Wavelet) {
+    if signal.len() < 2 {
+        return;
+    }
+    let mut low = signal.iter().copied().step_by(2).collect::<Vec<_>>();
+    let mut high = signal
+        .iter()
+        .copied()
+        .skip(1)
+        .step_by(2)
+        .collect::<Vec<_>>();
+    match wavelet {
+        Wavelet::Cdf97 => {
+            predict(&low, &mut high, |a, b| ALPHA * (a + b));
+            update(&mut low, &high, |a, b| BETA * (a + b));
+            predict(&low, &mut high, |a, b| GAMMA * (a + b));
+            update(&mut low, &high, |a, b| DELTA * (a + b));
+            low.iter_mut()
+                .for_each(|value| *value *= std::f64::consts::SQRT_2 / K);
+            high.iter_mut()
+                .for_each(|value| *value *= K / std::f64::consts::SQRT_2);
+        }
+        Wavelet::LeGall53 => {
+            predict(&low, &mut high, |a, b| -((a + b) / 2.).floor());
+            update(&mut low, &high, |a, b| ((a + b + 2.) / 4.).floor());
+        }
+    }
+    let (low_half, high_half) = signal.split_at_mut(low.len());
+    low_half.copy_from_slice(&low);
+    high_half.copy_from_slice(&high);
+}
+
+// Inverse of forward_1d(), undoing the lifting steps in reverse order
+pub fn inverse_1d(signal: &mut [f64], wavelet: Wavelet) {
+    if signal.len() < 2 {
+        return;
+    }
+    let (low_half, high_half) = signal.split_at(signal.len().div_ceil(2));
+    let (mut low, mut high) = (low_half.to_vec(), high_half.to_vec());
+    match wavelet {
+        Wavelet::Cdf97 => {
+            low.iter_mut()
+                .for_each(|value| *value *= K / std::f64::consts::SQRT_2);
+            high.iter_mut()
+                .for_each(|value| *value *= std::f64::consts::SQRT_2 / K);
+            update(&mut low, &high, |a, b| -DELTA * (a + b));
+            predict(&low, &mut high, |a, b| -GAMMA * (a + b));
+            update(&mut low, &high, |a, b| -BETA * (a + b));
+            predict(&low, &mut high, |a, b| -ALPHA * (a + b));
+        }
+        Wavelet::LeGall53 => {
+            update(&mut low, &high, |a, b| -((a + b + 2.) / 4.).floor());
+            predict(&low, &mut high, |a, b| ((a + b) / 2.).floor());
+        }
+    }
+    for (index, value) in signal.iter_mut().enumerate() {
+        *value = match index % 2 {
+            0 => low[index / 2],
+            _ => high[index / 2],
+        };
+    }
+}
+
+// Adds a function of the even neighbours of each odd sample to it. The sample past the end
+// mirrors the one before it
+fn predict(low: &[f64], high: &mut [f64], lift: impl Fn(f64, f64) -> f64) {
+    for (index, value) in high.iter_mut().enumerate() {
+        *value += lift(low[index], low[(index + 1).min(low.len() - 1)]);
+    }
+}
+
+// Adds a function of the odd neighbours of each even sample to it, mirroring at both ends
+fn update(low: &mut [f64], high: &[f64], lift: impl Fn(f64, f64) -> f64) {
+    for (index, value) in low.iter_mut().enumerate() {
+        *value += lift(
+            high[index.saturating_sub(1)],
+            high[index.min(high.len() - 1)],
+        );
+    }
+}
+
+#[test]
+fn reconstructs_perfectly() {
+    use crate::generate::Random;
+
+    let mut random = Random::new(5);
+    for (width, height) in [(1, 1), (2, 3), (17, 9), (64, 48)] {
+        let values = (0..width * height)
+            .map(|_| random.below(256) as f64 - 128.)
+            .collect::<Vec<_>>();
+        for wavelet in [Wavelet::Cdf97, Wavelet::LeGall53] {
+            let mut coefficients = values.clone();
+            forward_2d(&mut coefficients, width, height, wavelet, 4);
+            if wavelet == Wavelet::LeGall53 {
+                // Integer lifting keeps integer samples as integers
+                assert!(coefficients.iter().all(|value| value.fract() == 0.));
+            }
+            inverse_2d(&mut coefficients, width, height, wavelet, 4);
+            for (a, b) in coefficients.iter().zip(&values) {
+                match wavelet {
+                    Wavelet::Cdf97 => assert!((a - b).abs() < 1e-9),
+                    Wavelet::LeGall53 => assert_eq!(a, b),
+                }
+            }
+        }
+    }
+
+    // The subbands cover the plane exactly once
+    let bands = subbands(17, 9, 3);
+    assert_eq!(bands.len(), 10);
+    let area: usize = bands.iter().map(|(xs, ys)| xs.len() * ys.len()).sum();
+    assert_eq!(area, 17 * 9);
+    assert_eq!(bands[9], (0..3, 0..2));
+}
+
+#[test]
+fn avoids_blocking() {
+    use crate::{
+        dct_2d::{self, QuantMatrices, QuantPreset},
+        generate::{generate_frame, Pattern},
+        metrics::psnr,
+        padding::Padding,
+        yuv4mpeg2::{ColorSpace, Header},
+    };
+
+    let header = Header {
+        width: 64,
+        height: 64,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    // A smooth ramp with a little grain, so that the rate changes smoothly with the step size
+    let mut frame = generate_frame(Pattern::Gradient, &header, 0);
+    let grain = generate_frame(Pattern::Noise, &header, 0);
+    for (value, noise) in frame.data_y.iter_mut().zip(&grain.data_y) {
+        *value = (*value as i32 + (*noise as i32 - 128) / 16).clamp(0, 255) as u8;
+    }
+
+    // Without quantisation the 5/3 wavelet is lossless
+    let lossless = quantise_frame(frame.clone(), Wavelet::LeGall53, 5, 0., 0.);
+    assert_eq!(lossless.to_vec(), frame.to_vec());
+
+    // The CDF 9/7 basis is close to orthonormal
+    for norm in subband_norms(Wavelet::Cdf97, 5) {
+        assert!((norm - 1.).abs() < 0.1, "{norm}");
+    }
+
+    // Mean step across the horizontal 8x8 block edges relative to the mean step elsewhere
+    let blockiness = |values: &[u8]| {
+        let (mut edges, mut inside) = (Vec::new(), Vec::new());
+        for row in values.chunks_exact(64) {
+            for x in 1..64 {
+                let step = row[x].abs_diff(row[x - 1]) as f64;
+                match x % 8 {
+                    0 => edges.push(step),
+                    _ => inside.push(step),
+                }
+            }
+        }
+        let mean = |steps: &[f64]| steps.iter().sum::<f64>() / steps.len() as f64;
+        mean(&edges) / mean(&inside).max(1e-9)
+    };
+
+    // The rate is estimated by the zeroth-order entropy of the quantised luma levels, in bits
+    let entropy = |levels: &[f64]| {
+        let mut counts = std::collections::HashMap::new();
+        for level in levels {
+            *counts.entry(*level as i64).or_insert(0) += 1;
+        }
+        let total = levels.len() as f64;
+        counts
+            .values()
+            .map(|&count| {
+                let count = count as f64;
+                -count * (count / total).log2()
+            })
+            .sum::<f64>()
+    };
+    let matrices = QuantMatrices::preset(QuantPreset::Flat).scaled(4., 4.);
+    let luma_matrix = dct_2d::resample_matrix(&matrices.luma, 8);
+    let dct_levels = dct_2d::divide(&frame.data_y, 64, 64, 8, Padding::Replicate)
+        .iter()
+        .flat_map(|block| dct_2d::quantise(dct_2d::transform(block, 8), &luma_matrix))
+        .collect::<Vec<_>>();
+    let dct_rate = entropy(&dct_levels);
+    let dct = dct_2d::quantise_frame(frame.clone(), &matrices, 8, Padding::Replicate);
+
+    for wavelet in [Wavelet::Cdf97, Wavelet::LeGall53] {
+        let norms = subband_norms(wavelet, 5);
+        let mut coefficients = frame
+            .data_y
+            .iter()
+            .map(|&value| value as f64 - 128.)
+            .collect::<Vec<_>>();
+        forward_2d(&mut coefficients, 64, 64, wavelet, 5);
+        let wavelet_rate = |step: f64| {
+            let mut levels = Vec::with_capacity(coefficients.len());
+            for ((xs, ys), norm) in subbands(64, 64, 5).into_iter().zip(&norms) {
+                for y in ys {
+                    for &value in &coefficients[y * 64 + xs.start..y * 64 + xs.end] {
+                        levels.push((value * norm / step).round());
+                    }
+                }
+            }
+            entropy(&levels)
+        };
+
+        // Coarser steps give lower rates, so the finest step at which the wavelet spends no more
+        // bits than the DCT is found by bisecting on a log scale. The rate moves in jumps as
+        // coefficients cross step boundaries, so it can only be matched approximately
+        let (mut fine, mut coarse) = (1f64, 1024f64);
+        for _ in 0..40 {
+            let step = (fine * coarse).sqrt();
+            match wavelet_rate(step) > dct_rate {
+                true => fine = step,
+                false => coarse = step,
+            }
+        }
+        let (step, rate) = (coarse, wavelet_rate(coarse));
+        assert!(
+            rate <= dct_rate && rate > 0.9 * dct_rate,
+            "{rate} {dct_rate}"
+        );
+
+        let quantised = quantise_frame(frame.clone(), wavelet, 5, step, step);
+        let (dct_psnr, wavelet_psnr) = (
+            psnr(&frame.data_y, &dct.data_y),
+            psnr(&frame.data_y, &quantised.data_y),
+        );
+        let (dct_blockiness, wavelet_blockiness) =
+            (blockiness(&dct.data_y), blockiness(&quantised.data_y));
+        dbg!(
+            wavelet,
+            dct_rate,
+            rate,
+            step,
+            dct_psnr,
+            wavelet_psnr,
+            dct_blockiness,
+            wavelet_blockiness
+        );
+        assert!(wavelet_blockiness < dct_blockiness);
+        assert!(wavelet_psnr > dct_psnr);
+    }
+}
@@ -1,61 +1,87 @@
 use std::{
-    io::{BufRead, BufReader, Read},
+    io::{BufRead, BufReader, ErrorKind, Read},
     str::FromStr,
 };
 
-use super::{ColorSpace, Error, Frame, Header, InterlaceMode, PixelAspectRatio};
+use super::{ColorSpace, Error, Frame, Header, InterlaceMode, Limits, PixelAspectRatio};
 
 pub struct Y4MReader<R: Read> {
     pub header: Header,
     source: BufReader<R>,
+    limits: Limits,
 }
 
 pub struct Decoder<R: Read> {
     source: BufReader<R>,
+    limits: Limits,
 }
 
 impl<R: Read> Decoder<R> {
     pub fn read_header(mut self) -> Result<Y4MReader<R>, Error> {
-        let mut header_buf = String::new();
-        self.source.read_line(&mut header_buf)?;
+        let header_buf = read_limited_line(&mut self.source, self.limits.max_header_length)?;
         let header = Header::from_str(&header_buf)?;
+        self.limits.check(&header)?;
         Ok(Y4MReader {
             header,
             source: self.source,
+            limits: self.limits,
         })
     }
 
     pub fn new(reader: R) -> Self {
         Decoder {
             source: BufReader::new(reader),
+            limits: Limits::default(),
         }
     }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+// Reads up to and including the next newline, failing rather than buffering more than max_length bytes
+fn read_limited_line<R: Read>(
+    source: &mut BufReader<R>,
+    max_length: usize,
+) -> Result<String, Error> {
+    let mut line = String::new();
+    source.take(max_length as u64 + 1).read_line(&mut line)?;
+    if line.len() > max_length {
+        return Err(Error::HeaderTooLong(max_length));
+    }
+    Ok(line)
 }
 
 pub struct FrameIterator<R: Read> {
     reader: Y4MReader<R>,
+    finished: bool,
 }
 
 impl<I: Read> Iterator for FrameIterator<I> {
-    type Item = Frame;
+    type Item = Result<Frame, Error>;
 
+    // Ends after the first error, as the position of the next frame in the stream is unknown
     fn next(&mut self) -> Option<Self::Item> {
-        let next_frame = self.reader.next_frame();
-        match next_frame {
-            Ok(frame) => frame,
-            Err(_) => None,
+        if self.finished {
+            return None;
         }
+        let next_frame = self.reader.next_frame().transpose();
+        self.finished = !matches!(next_frame, Some(Ok(_)));
+        next_frame
     }
 }
 
 impl<R: Read> IntoIterator for Y4MReader<R> {
-    type Item = Frame;
+    type Item = Result<Frame, Error>;
 
     type IntoIter = FrameIterator<R>;
 
     fn into_iter(self) -> Self::IntoIter {
         FrameIterator {
             reader: self,
+            finished: false,
         }
     }
 
@@ -64,21 +90,25 @@ impl<R: Read> IntoIterator for Y4MReader<R> {
 impl<R: Read> Y4MReader<R> {
     // todo: make this an iterator
     pub fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
-        let mut frame_buf = String::new();
-        self.source.read_line(&mut frame_buf)?;
+        let frame_buf = read_limited_line(&mut self.source, self.limits.max_header_length)?;
 
         if frame_buf.is_empty() {
             // end of file
             Ok(None)
+        } else if !frame_buf.starts_with("FRAME") {
+            Err(Error::DecodeFrame)
         } else {
             let mut buf = vec![0; self.header.frame_bytes_length()];
-            self.source.read_exact(&mut buf)?;
+            self.source.read_exact(&mut buf).map_err(|error| match error.kind() {
+                ErrorKind::UnexpectedEof => Error::TruncatedFrame,
+                _ => Error::IOError(error),
+            })?;
             let frame = Frame::from_buf(
                 &buf,
                 self.header.width,
                 self.header.height,
                 self.header.color_space,
-            );
+            )?;
             Ok(Some(frame))
         }
     }
@@ -118,10 +148,9 @@ impl FromStr for Header {
                             header.height = height;
                         }
                         'F' => {
-                            parameter_string.split_once(':');
                             let (numerator, denominator) = parameter_string[1..]
                                 .split_once(':')
-                                .expect("Unable to parse frame rate");
+                                .ok_or(Error::DecodeFrameRate)?;
                             header.frame_rate_numerator =
                                 numerator.parse().map_err(|_| Error::DecodeFrameRate)?;
                             header.frame_rate_denominator =
@@ -144,18 +173,7 @@ impl FromStr for Header {
                             "A32:27" => header.pixel_aspect_ratio = PixelAspectRatio::NtscDvdWide,
                             _ => header.pixel_aspect_ratio = PixelAspectRatio::Unknown,
                         },
-                        'C' => match parameter_string {
-                            "C420jpeg" => header.color_space = ColorSpace::C420jpeg,
-                            "C420paldv" => header.color_space = ColorSpace::C420paldv,
-                            "C420" => header.color_space = ColorSpace::C420,
-                            "C422" => header.color_space = ColorSpace::C422,
-                            "C444" => header.color_space = ColorSpace::C444,
-                            "Cmono" => header.color_space = ColorSpace::Cmono,
-                            "C420mpeg2" => header.color_space = ColorSpace::C420mpeg2,
-                            _ => {
-                                return Err(Error::DecodeColorSpace);
-                            }
-                        },
+                        'C' => header.color_space = parameter_string.parse()?,
                         'X' => {} // ignore comments
                         _ => {}   // unknown, ignore
                     }
@@ -169,3 +187,166 @@ impl FromStr for Header {
         Ok(header)
     }
 }
+
+impl FromStr for ColorSpace {
+    type Err = Error;
+
+    // Parses a color space in the form used in the header, e.g. C420jpeg
+    fn from_str(color_space_string: &str) -> Result<Self, Error> {
+        match color_space_string {
+            "C420jpeg" => Ok(ColorSpace::C420jpeg),
+            "C420paldv" => Ok(ColorSpace::C420paldv),
+            "C420" => Ok(ColorSpace::C420),
+            "C422" => Ok(ColorSpace::C422),
+            "C444" => Ok(ColorSpace::C444),
+            "Cmono" => Ok(ColorSpace::Cmono),
+            "C420mpeg2" => Ok(ColorSpace::C420mpeg2),
+            _ => Err(Error::DecodeColorSpace),
+        }
+    }
+}
+
+#[test]
+fn rejects_hostile_headers() {
+    assert!(matches!(
+        Header::from_str("YUV4MPEG2 W10 H10 F30"),
+        Err(Error::DecodeFrameRate)
+    ));
+
+    let huge = "YUV4MPEG2 W100000 H100000 F25:1 C420jpeg\n".as_bytes();
+    assert!(matches!(
+        Decoder::new(huge).read_header(),
+        Err(Error::DimensionsTooLarge(100000, 100000, _, _))
+    ));
+
+    let endless = vec![b'X'; 100000];
+    assert!(matches!(
+        Decoder::new(endless.as_slice()).read_header(),
+        Err(Error::HeaderTooLong(_))
+    ));
+}
+
+#[test]
+fn survives_corrupted_streams() {
+    use crate::generate::{generate_frame, Pattern, Random};
+
+    let header = Header {
+        width: 16,
+        height: 8,
+        frame_rate_numerator: 25,
+        frame_rate_denominator: 1,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let mut stream = header.to_string().into_bytes();
+    for index in 0..3 {
+        stream.extend_from_slice(b"FRAME\n");
+        stream.extend(generate_frame(Pattern::Noise, &header, index).to_vec());
+    }
+
+    let tokens = [
+        " ", "\n", ":", "W", "H", "F", "I", "A", "C", "X", "0", "-1", "é", "FRAME", "Ip",
+        "C420", "Cmono", "99999999999999999999999",
+    ];
+    let limits = Limits {
+        max_width: 64,
+        max_height: 64,
+        max_frame_bytes: 1 << 14,
+        max_header_length: 64,
+    };
+    let mut random = Random::new(34);
+
+    for _ in 0..5000 {
+        let mut corrupted = stream.clone();
+        for _ in 0..1 + random.below(4) {
+            // Mostly corrupt the stream header, where the parsing happens
+            let range = match random.below(2) {
+                0 => corrupted.len().min(48),
+                _ => corrupted.len(),
+            };
+            let position = random.below(range);
+            match random.below(4) {
+                0 if position < corrupted.len() => corrupted[position] = random.next_u64() as u8,
+                1 => corrupted.truncate(position),
+                2 => {
+                    let token = tokens[random.below(tokens.len())].bytes();
+                    corrupted.splice(position..position, token);
+                }
+                _ => {
+                    let end = (position + 1 + random.below(8)).min(corrupted.len());
+                    corrupted.drain(position..end);
+                }
+            }
+        }
+
+        // Decoding may fail, but must not panic and must respect the limits
+        let decoder = Decoder::new(corrupted.as_slice()).with_limits(limits);
+        if let Ok(mut reader) = decoder.read_header() {
+            assert!(reader.header.frame_bytes_length() <= limits.max_frame_bytes);
+            while let Ok(Some(frame)) = reader.next_frame() {
+                assert_eq!(frame.to_vec().len(), reader.header.frame_bytes_length());
+            }
+        }
+    }
+}
+
+#[test]
+fn reports_truncated_and_corrupt_frames() {
+    let header = Header {
+        width: 8,
+        height: 8,
+        interlace_mode: InterlaceMode::Ip,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let mut stream = header.to_string().into_bytes();
+    for _ in 0..2 {
+        stream.extend_from_slice(b"FRAME\n");
+        stream.extend(vec![128; header.frame_bytes_length()]);
+    }
+    let decode = |stream: &[u8]| {
+        Decoder::new(stream)
+            .read_header()
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>()
+    };
+    assert!(decode(&stream).iter().all(Result::is_ok));
+
+    // Errors end the frames rather than being mistaken for the end of the file
+    let truncated = decode(&stream[..stream.len() - 1]);
+    assert_eq!(truncated.len(), 2);
+    assert!(truncated[0].is_ok());
+    assert!(matches!(truncated[1], Err(Error::TruncatedFrame)));
+
+    let mut corrupt = stream.clone();
+    corrupt.extend_from_slice(b"FRAMX\n");
+    corrupt.extend(vec![128; header.frame_bytes_length()]);
+    let corrupt = decode(&corrupt);
+    assert_eq!(corrupt.len(), 3);
+    assert!(matches!(corrupt[2], Err(Error::DecodeFrame)));
+}
+
+#[test]
+fn decodes_monochrome_frames() {
+    // Cmono frames hold a luma plane only, so each frame is width * height bytes
+    let stream = [
+        b"YUV4MPEG2 W8 H4 F25:1 Ip Cmono\n".as_slice(),
+        b"FRAME\n",
+        &[10; 32],
+        b"FRAME\n",
+        &[20; 32],
+    ]
+    .concat();
+    let reader = Decoder::new(stream.as_slice()).read_header().unwrap();
+    assert_eq!(reader.header.color_space, ColorSpace::Cmono);
+    assert_eq!(reader.header.frame_bytes_length(), 32);
+
+    let frames = reader.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(frames.len(), 2);
+    for (frame, value) in frames.iter().zip([10, 20]) {
+        assert_eq!(frame.data_y, vec![value; 32]);
+        assert!(frame.data_cb.is_empty() && frame.data_cr.is_empty());
+        assert_eq!((frame.chroma_width(), frame.chroma_height()), (0, 0));
+    }
+}
@@ -14,9 +14,9 @@ pub struct Encoder<W: Write> {
 impl<W: Write> Encoder<W> {
     pub fn write_header(mut self, header: &Header) -> Result<Y4MWriter<W>, Error> {
         let header_string = header.to_string();
-        self.sink.write(header_string.as_bytes())?;
+        self.sink.write_all(header_string.as_bytes())?;
         Ok(Y4MWriter {
-            header: header.clone(),
+            header: *header,
             sink: self.sink,
         })
     }
@@ -31,21 +31,22 @@ impl<W: Write> Encoder<W> {
 impl<W: Write> Y4MWriter<W> {
     pub fn write_frame(&mut self, frame: Frame) -> Result<(), Error> {
         let frame_marker_string = "FRAME\n";
-        self.sink.write(frame_marker_string.as_bytes())?;
+        self.sink.write_all(frame_marker_string.as_bytes())?;
 
         let buf = frame.to_vec();
-        self.sink.write(&buf)?;
+        self.sink.write_all(&buf)?;
         Ok(())
     }
 }
 
-impl ToString for Header {
-    // Parses a yuv4mpeg2 header of the form described at https://wiki.multimedia.cx/index.php/YUV4MPEG2
-    // into an instance of 'Header'
-    fn to_string(&self) -> String {
-        let header_string = format!(
+impl std::fmt::Display for Header {
+    // Formats an instance of 'Header' as a yuv4mpeg2 header of the form described at
+    // https://wiki.multimedia.cx/index.php/YUV4MPEG2
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
             "YUV4MPEG2 W{width} H{height} F{num}:{den} {inter_mode_string} \
-            {aspect_string} {color_string}\n",
+            {aspect_string} {color_string}",
             width = self.width,
             height = self.height,
             num = self.frame_rate_numerator,
@@ -53,9 +54,7 @@ impl ToString for Header {
             inter_mode_string = self.interlace_mode,
             aspect_string = self.pixel_aspect_ratio,
             color_string = self.color_space,
-        );
-
-        header_string
+        )
     }
 }
 
@@ -0,0 +1,265 @@
+use std::{fs::File, path::Path, str::FromStr};
+
+use memmap2::Mmap;
+
+use super::{
+    chroma_dimensions_from_space, chroma_len_from_space, ColorSpace, Error, Frame, Header, Limits,
+};
+
+// Reads a YUV4MPEG2 file by mapping it into memory. The frames are indexed up front, after
+// which any frame can be borrowed without copying, in any order and from any thread
+pub struct MappedReader {
+    pub header: Header,
+    map: Mmap,
+    // Offset of the first byte of each frame's pixel data
+    frame_offsets: Vec<usize>,
+}
+
+// A frame whose planes borrow directly from the mapped file
+#[derive(Clone, Copy)]
+pub struct FrameRef<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub color_space: ColorSpace,
+    pub data_y: &'a [u8],
+    pub data_cb: &'a [u8],
+    pub data_cr: &'a [u8],
+}
+
+impl MappedReader {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::open_with_limits(path, Limits::default())
+    }
+
+    pub fn open_with_limits(path: &Path, limits: Limits) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        // Safety: the map is only read, and the file is assumed not to be truncated or
+        // modified by another process while it is mapped
+        let map = unsafe { Mmap::map(&file)? };
+        let (header, frame_offsets) = index_frames(&map, limits)?;
+        Ok(MappedReader {
+            header,
+            map,
+            frame_offsets,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.frame_offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frame_offsets.is_empty()
+    }
+
+    pub fn frame(&self, index: usize) -> Option<FrameRef<'_>> {
+        let offset = *self.frame_offsets.get(index)?;
+        let header = &self.header;
+        let y_len = header.width * header.height;
+        let chroma_len = chroma_len_from_space(header.color_space, header.width, header.height);
+        let data = &self.map[offset..offset + header.frame_bytes_length()];
+        Some(FrameRef {
+            width: header.width,
+            height: header.height,
+            color_space: header.color_space,
+            data_y: &data[..y_len],
+            data_cb: &data[y_len..y_len + chroma_len],
+            data_cr: &data[y_len + chroma_len..],
+        })
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = FrameRef<'_>> {
+        (0..self.len()).filter_map(|index| self.frame(index))
+    }
+}
+
+impl<'a> FrameRef<'a> {
+    // Data, width and height of each plane, in Y, Cb, Cr order, as Frame::planes()
+    pub fn planes(&self) -> [(&'a [u8], usize, usize); 3] {
+        let (chroma_width, chroma_height) =
+            chroma_dimensions_from_space(self.color_space, self.width, self.height);
+        [
+            (self.data_y, self.width, self.height),
+            (self.data_cb, chroma_width, chroma_height),
+            (self.data_cr, chroma_width, chroma_height),
+        ]
+    }
+
+    // Copies the planes into an owned frame
+    pub fn to_frame(&self) -> Frame {
+        Frame {
+            width: self.width,
+            height: self.height,
+            color_space: self.color_space,
+            data_y: self.data_y.to_vec(),
+            data_cb: self.data_cb.to_vec(),
+            data_cr: self.data_cr.to_vec(),
+        }
+    }
+}
+
+// Parses the stream header and finds where each frame's data starts. As with the streaming
+// decoder, a truncated frame at the end of the file is an error
+fn index_frames(data: &[u8], limits: Limits) -> Result<(Header, Vec<usize>), Error> {
+    let header_end = line_end(data, 0, limits.max_header_length)?.ok_or(Error::DecodeHeader)?;
+    let header_string =
+        std::str::from_utf8(&data[..header_end]).map_err(|_| Error::DecodeHeader)?;
+    let header = Header::from_str(header_string)?;
+    limits.check(&header)?;
+
+    let frame_bytes = header.frame_bytes_length();
+    let mut frame_offsets = Vec::new();
+    let mut offset = header_end;
+    while offset < data.len() {
+        if !data[offset..].starts_with(b"FRAME") {
+            return Err(Error::DecodeFrame);
+        }
+        let Some(data_start) = line_end(data, offset, limits.max_header_length)? else {
+            return Err(Error::TruncatedFrame);
+        };
+        if data.len() - data_start < frame_bytes {
+            return Err(Error::TruncatedFrame);
+        }
+        frame_offsets.push(data_start);
+        offset = data_start + frame_bytes;
+    }
+    Ok((header, frame_offsets))
+}
+
+// Position just past the newline ending the line that starts at `start`, or None if the data
+// ends first. Lines longer than max_length are an error
+fn line_end(data: &[u8], start: usize, max_length: usize) -> Result<Option<usize>, Error> {
+    let search_end = data.len().min(start.saturating_add(max_length));
+    match data[start..search_end]
+        .iter()
+        .position(|&byte| byte == b'\n')
+    {
+        Some(position) => Ok(Some(start + position + 1)),
+        None if search_end == data.len() => Ok(None),
+        None => Err(Error::HeaderTooLong(max_length)),
+    }
+}
+
+#[test]
+fn maps_frames_without_copying() {
+    use crate::generate::{generate_frame, Pattern};
+
+    let header = Header {
+        width: 24,
+        height: 16,
+        frame_rate_numerator: 25,
+        frame_rate_denominator: 1,
+        interlace_mode: super::InterlaceMode::Ip,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let frames = (0..5)
+        .map(|index| generate_frame(Pattern::Noise, &header, index))
+        .collect::<Vec<_>>();
+    let mut stream = header.to_string().into_bytes();
+    for frame in &frames {
+        stream.extend_from_slice(b"FRAME\n");
+        stream.extend(frame.to_vec());
+    }
+
+    // A truncated final frame is reported rather than dropped
+    let path = std::env::temp_dir().join(format!("squish-mmap-{}.y4m", std::process::id()));
+    let truncated = [stream.as_slice(), b"FRAME\n\x01\x02"].concat();
+    std::fs::write(&path, truncated).unwrap();
+    assert!(matches!(
+        MappedReader::open(&path),
+        Err(Error::TruncatedFrame)
+    ));
+
+    std::fs::write(&path, &stream).unwrap();
+    let reader = MappedReader::open(&path).unwrap();
+    assert_eq!(reader.len(), frames.len());
+
+    // Frames can be read out of order from several threads at once
+    std::thread::scope(|scope| {
+        for index in (0..frames.len()).rev() {
+            let (reader, frames) = (&reader, &frames);
+            scope.spawn(move || {
+                let frame = reader.frame(index).unwrap();
+                assert_eq!(frame.to_frame().to_vec(), frames[index].to_vec());
+                assert_eq!(frame.planes(), frames[index].planes());
+            });
+        }
+    });
+    assert!(reader.frame(frames.len()).is_none());
+
+    drop(reader);
+    std::fs::remove_file(&path).unwrap();
+}
+
+// Timing comparison of reading every frame with the streaming decoder, which copies each frame
+// into a new buffer, and borrowing every frame from the map. Ignored by default as it writes a
+// large file; run with `cargo test --release -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn borrows_frames_faster_than_streaming() {
+    use crate::{
+        generate::{generate_frame, Pattern},
+        yuv4mpeg2::{Decoder, InterlaceMode},
+    };
+    use std::time::{Duration, Instant};
+
+    let header = Header {
+        width: 1280,
+        height: 720,
+        frame_rate_numerator: 25,
+        frame_rate_denominator: 1,
+        interlace_mode: InterlaceMode::Ip,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    let frame = generate_frame(Pattern::Noise, &header, 0).to_vec();
+    let mut stream = header.to_string().into_bytes();
+    for _ in 0..100 {
+        stream.extend_from_slice(b"FRAME\n");
+        stream.extend_from_slice(&frame);
+    }
+    let path = std::env::temp_dir().join(format!("squish-timing-{}.y4m", std::process::id()));
+    std::fs::write(&path, &stream).unwrap();
+
+    // Each pass reads one sample of every 64 bytes, so the time is dominated by getting the
+    // frames rather than by looking at them. The best of several runs is kept
+    let checksum = |planes: [&[u8]; 3]| -> u64 {
+        planes
+            .iter()
+            .flat_map(|plane| plane.iter().step_by(64))
+            .map(|&value| value as u64)
+            .sum()
+    };
+    fn best_of(mut pass: impl FnMut() -> u64) -> (Duration, u64) {
+        (0..5)
+            .map(|_| {
+                let start = Instant::now();
+                let sum = pass();
+                (start.elapsed(), sum)
+            })
+            .min()
+            .unwrap()
+    }
+    let (streamed, streamed_sum) = best_of(|| {
+        let file = std::fs::File::open(&path).unwrap();
+        let reader = Decoder::new(file).read_header().unwrap();
+        reader
+            .into_iter()
+            .map(|frame| frame.unwrap())
+            .map(|frame| checksum([&frame.data_y, &frame.data_cb, &frame.data_cr]))
+            .sum()
+    });
+    let (mapped, mapped_sum) = best_of(|| {
+        let reader = MappedReader::open(&path).unwrap();
+        reader
+            .frames()
+            .map(|frame| checksum([frame.data_y, frame.data_cb, frame.data_cr]))
+            .sum()
+    });
+    std::fs::remove_file(&path).unwrap();
+
+    dbg!(streamed, mapped);
+    assert_eq!(streamed_sum, mapped_sum);
+    assert!(mapped < streamed);
+}
@@ -1,6 +1,7 @@
 // Coding based on description here: https://wiki.multimedia.cx/index.php/YUV4MPEG2
 pub mod decode;
 pub mod encode;
+pub mod mmap;
 pub use decode::Decoder;
 pub use encode::Encoder;
 
@@ -13,36 +14,83 @@ pub struct Frame {
     pub data_cb: Vec<u8>,
     pub data_cr: Vec<u8>,
 }
-fn chroma_len_from_space(color_space: ColorSpace, width: usize, height: usize) -> usize {
-    match color_space {
-        ColorSpace::C444 => height * width,
-        ColorSpace::C422 => height * width / 2,
-        ColorSpace::C420 | ColorSpace::C420jpeg | ColorSpace::C420mpeg2 | ColorSpace::C420paldv => {
-            height * width / 4
-        }
-        ColorSpace::Cmono => height * width, // TODO: check definition of Cmono
+// Width and height of each chroma plane. Subsampled planes round up, so the last column or row
+// of chroma covers a partial pair of luma samples when a dimension is odd
+fn chroma_dimensions_from_space(
+    color_space: ColorSpace,
+    width: usize,
+    height: usize,
+) -> (usize, usize) {
+    if color_space == ColorSpace::Cmono {
+        return (0, 0); // luma plane only
     }
+    let (horizontal, vertical) = color_space.subsampling();
+    (width.div_ceil(horizontal), height.div_ceil(vertical))
+}
+// Saturates rather than overflowing so that absurd dimensions can be caught by the decode limits
+fn chroma_len_from_space(color_space: ColorSpace, width: usize, height: usize) -> usize {
+    let (chroma_width, chroma_height) = chroma_dimensions_from_space(color_space, width, height);
+    chroma_width.saturating_mul(chroma_height)
 }
 impl Frame {
     pub fn chroma_len(&self) -> usize {
         chroma_len_from_space(self.color_space, self.width, self.height)
     }
-    pub fn from_buf(buf: &[u8], width: usize, height: usize, color_space: ColorSpace) -> Self {
-        let y_len = height * width;
+    pub fn chroma_width(&self) -> usize {
+        chroma_dimensions_from_space(self.color_space, self.width, self.height).0
+    }
+    pub fn chroma_height(&self) -> usize {
+        chroma_dimensions_from_space(self.color_space, self.width, self.height).1
+    }
+    // Data, width and height of each plane, in Y, Cb, Cr order
+    pub fn planes(&self) -> [(&[u8], usize, usize); 3] {
+        let (chroma_width, chroma_height) = (self.chroma_width(), self.chroma_height());
+        [
+            (&self.data_y, self.width, self.height),
+            (&self.data_cb, chroma_width, chroma_height),
+            (&self.data_cr, chroma_width, chroma_height),
+        ]
+    }
+    // Builds a frame of the same size by applying an operation to each plane.
+    // The operation is given the plane index (0 for Y, 1 for Cb, 2 for Cr), data, width and height
+    pub fn map_planes(
+        &self,
+        mut operation: impl FnMut(usize, &[u8], usize, usize) -> Vec<u8>,
+    ) -> Frame {
+        let [y, cb, cr] = self.planes();
+        Frame {
+            width: self.width,
+            height: self.height,
+            color_space: self.color_space,
+            data_y: operation(0, y.0, y.1, y.2),
+            data_cb: operation(1, cb.0, cb.1, cb.2),
+            data_cr: operation(2, cr.0, cr.1, cr.2),
+        }
+    }
+    pub fn from_buf(
+        buf: &[u8],
+        width: usize,
+        height: usize,
+        color_space: ColorSpace,
+    ) -> Result<Self, Error> {
+        let y_len = height.saturating_mul(width);
         let chroma_len = chroma_len_from_space(color_space, width, height);
+        if buf.len() < y_len.saturating_add(chroma_len.saturating_mul(2)) {
+            return Err(Error::DecodeFrame);
+        }
 
         let data_y = buf[..y_len].to_vec();
         let data_cb = buf[y_len..y_len + chroma_len].to_vec();
         let data_cr = buf[y_len + chroma_len..y_len + 2 * chroma_len].to_vec();
 
-        Frame {
+        Ok(Frame {
             width,
             height,
             color_space,
             data_y,
             data_cb,
             data_cr,
-        }
+        })
     }
 
     pub fn to_vec(&self) -> Vec<u8> {
@@ -67,6 +115,20 @@ pub enum Error {
     DecodeFrameRate,
     #[error("Unable to parse interlace mode")]
     DecodeInterlaceMode,
+    #[error("Unable to parse frame")]
+    DecodeFrame,
+    #[error("File ends partway through a frame")]
+    TruncatedFrame,
+    #[error("Header is longer than the limit of {0} bytes")]
+    HeaderTooLong(usize),
+    #[error("Frame dimensions {0}x{1} exceed the limit of {2}x{3}")]
+    DimensionsTooLarge(usize, usize, usize, usize),
+    #[error("Frames of {0} bytes exceed the limit of {1} bytes")]
+    FrameTooLarge(usize, usize),
+    #[error("Frame dimensions differ ({0}x{1} and {2}x{3})")]
+    IncompatibleDimensions(usize, usize, usize, usize),
+    #[error("Color spaces differ ({0} and {1})")]
+    IncompatibleColorSpace(ColorSpace, ColorSpace),
 
     #[error(transparent)]
     IOError(#[from] std::io::Error),
@@ -83,7 +145,7 @@ pub struct Header {
     pub color_space: ColorSpace,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InterlaceMode {
     Unknown,
     Ip,
@@ -92,6 +154,14 @@ pub enum InterlaceMode {
     Im,
 }
 
+impl InterlaceMode {
+    // True when every frame holds two fields captured at different times. Mixed mode (Im)
+    // signals this per frame, which isn't parsed, so it is treated as progressive
+    pub fn is_interlaced(&self) -> bool {
+        matches!(self, InterlaceMode::It | InterlaceMode::Ib)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum PixelAspectRatio {
     Unknown,
@@ -101,7 +171,7 @@ pub enum PixelAspectRatio {
     NtscDvdWide,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ColorSpace {
     C420jpeg,  // 4:2:0 with biaxially-displaced chroma planes
     C420paldv, // 4:2:0 with vertically-displaced chroma planes
@@ -112,6 +182,19 @@ pub enum ColorSpace {
     C420mpeg2, // TODO: investigate
 }
 
+impl ColorSpace {
+    // Horizontal and vertical factors by which the chroma planes are subsampled
+    pub fn subsampling(&self) -> (usize, usize) {
+        match self {
+            ColorSpace::C444 | ColorSpace::Cmono => (1, 1),
+            ColorSpace::C422 => (2, 1),
+            ColorSpace::C420 | ColorSpace::C420jpeg | ColorSpace::C420mpeg2 | ColorSpace::C420paldv => {
+                (2, 2)
+            }
+        }
+    }
+}
+
 impl Default for Header {
     fn default() -> Self {
         Header {
@@ -126,17 +209,142 @@ impl Default for Header {
     }
 }
 
+// Bounds on what a decoder will accept, so that a hostile or corrupt header can't make it
+// allocate unbounded amounts of memory
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_width: usize,
+    pub max_height: usize,
+    pub max_frame_bytes: usize,
+    // Applies to the stream header line and to each frame header line
+    pub max_header_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_width: 16384,
+            max_height: 16384,
+            max_frame_bytes: 1 << 30,
+            max_header_length: 4096,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check(&self, header: &Header) -> Result<(), Error> {
+        if header.width > self.max_width || header.height > self.max_height {
+            return Err(Error::DimensionsTooLarge(
+                header.width,
+                header.height,
+                self.max_width,
+                self.max_height,
+            ));
+        }
+        let frame_bytes = header.frame_bytes_length();
+        if frame_bytes > self.max_frame_bytes {
+            return Err(Error::FrameTooLarge(frame_bytes, self.max_frame_bytes));
+        }
+        Ok(())
+    }
+}
+
 impl Header {
+    // Checks that frames described by the other header can be stored in the same file as this one
+    pub fn check_compatible(&self, other: &Header) -> Result<(), Error> {
+        if (self.width, self.height) != (other.width, other.height) {
+            return Err(Error::IncompatibleDimensions(
+                self.width,
+                self.height,
+                other.width,
+                other.height,
+            ));
+        }
+        if self.color_space != other.color_space {
+            return Err(Error::IncompatibleColorSpace(self.color_space, other.color_space));
+        }
+        Ok(())
+    }
+
     pub fn frame_bytes_length(&self) -> usize {
-        let colorspace_multiplier = match self.color_space {
-            ColorSpace::C420
-            | ColorSpace::C420jpeg
-            | ColorSpace::C420mpeg2
-            | ColorSpace::C420paldv => 3. / 2.,
-            ColorSpace::C422 => 2.,
-            ColorSpace::C444 => 3.,
-            ColorSpace::Cmono => 1., // todo: check
-        };
-        (self.width as f32 * self.height as f32 * colorspace_multiplier) as usize
+        let chroma_len = chroma_len_from_space(self.color_space, self.width, self.height);
+        self.width
+            .saturating_mul(self.height)
+            .saturating_add(chroma_len.saturating_mul(2))
+    }
+}
+
+#[test]
+fn checks_headers_are_compatible() {
+    let header = Header {
+        width: 16,
+        height: 8,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    // Frame rate and interlacing don't change how frames are stored
+    let other = Header {
+        frame_rate_numerator: 25,
+        interlace_mode: InterlaceMode::It,
+        ..header
+    };
+    assert!(header.check_compatible(&other).is_ok());
+    assert!(matches!(
+        header.check_compatible(&Header {
+            height: 10,
+            ..header
+        }),
+        Err(Error::IncompatibleDimensions(16, 8, 16, 10))
+    ));
+    assert!(matches!(
+        header.check_compatible(&Header {
+            color_space: ColorSpace::C444,
+            ..header
+        }),
+        Err(Error::IncompatibleColorSpace(
+            ColorSpace::C420jpeg,
+            ColorSpace::C444
+        ))
+    ));
+}
+
+#[test]
+fn round_trips_odd_sized_420_frames() {
+    // 4:2:0 chroma planes round up, so a 15x9 frame has 8x5 chroma planes
+    let header = Header {
+        width: 15,
+        height: 9,
+        interlace_mode: InterlaceMode::Ip,
+        color_space: ColorSpace::C420jpeg,
+        ..Header::default()
+    };
+    assert_eq!(header.frame_bytes_length(), 15 * 9 + 2 * 8 * 5);
+    let frames = (0..3)
+        .map(|index| Frame {
+            width: 15,
+            height: 9,
+            color_space: ColorSpace::C420jpeg,
+            data_y: vec![index; 15 * 9],
+            data_cb: vec![index + 100; 8 * 5],
+            data_cr: vec![index + 200; 8 * 5],
+        })
+        .collect::<Vec<_>>();
+    assert_eq!((frames[0].chroma_width(), frames[0].chroma_height()), (8, 5));
+    assert_eq!(frames[0].chroma_len(), 8 * 5);
+
+    let mut stream = vec![];
+    let mut writer = Encoder::new(&mut stream).write_header(&header).unwrap();
+    for frame in &frames {
+        writer.write_frame(frame.clone()).unwrap();
     }
+    drop(writer);
+
+    let decoded = Decoder::new(stream.as_slice())
+        .read_header()
+        .unwrap()
+        .into_iter()
+        .map(|frame| frame.unwrap().to_vec())
+        .collect::<Vec<_>>();
+    let expected = frames.iter().map(Frame::to_vec).collect::<Vec<_>>();
+    assert_eq!(decoded, expected);
 }